@@ -0,0 +1,398 @@
+use super::engine::LOOP_COUNT;
+use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre};
+
+/// A stereo frame, left channel first.
+pub type Stereo = [f32; 2];
+
+/// A selection of input channels, bit `n` stands for input `n`.
+pub type InputMask = u8;
+
+/// A frame of every input channel the engine can have.
+pub type InputFrame = [f32; ChannelConfig::MAX_CHANNELS];
+
+/// Every input channel there is.
+pub const ALL_INPUTS: InputMask = InputMask::MAX;
+
+/// Where a loop records from, any combination of input channels, the pad bus and the master mix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopSource {
+    /// The input channels.
+    pub inputs: InputMask,
+    /// Whether the sample pads are recorded.
+    pub pads: bool,
+    /// Whether the master mix (everything but the metronome) is resampled.
+    pub master: bool,
+}
+
+impl Default for LoopSource {
+    fn default() -> Self {
+        LoopSource {
+            inputs: ALL_INPUTS,
+            pads: true,
+            master: false,
+        }
+    }
+}
+
+impl LoopSource {
+    /// Switch to the next choice of inputs: all of them, each one on its own, each stereo pair,
+    /// then none at all.
+    pub fn cycle_inputs(&mut self, channels: ChannelConfig) {
+        let all = channels.all_inputs();
+        let mut choices = vec![all];
+        choices.extend((0..channels.inputs).map(|channel| 1 << channel));
+        choices.extend(
+            (0..channels.inputs.saturating_sub(1))
+                .step_by(2)
+                .map(|channel| 0b11 << channel),
+        );
+        choices.push(0);
+        choices.dedup();
+        let index = choices
+            .iter()
+            .position(|&choice| choice == self.inputs & all)
+            .map_or(0, |index| (index + 1) % choices.len());
+        self.inputs = choices[index];
+    }
+
+    /// How many channels a loop recording this source with `channels` stores: two when it mixes
+    /// several inputs or the stereo master mix, one otherwise.
+    pub fn channels(&self, channels: ChannelConfig) -> usize {
+        let stereo_inputs = (self.inputs & channels.all_inputs()).count_ones() > 1;
+        let stereo_master = self.master && channels.outputs > 1;
+        if stereo_inputs || stereo_master { 2 } else { 1 }
+    }
+
+    /// Describe the source for a user interface with `channels`.
+    pub fn describe(&self, channels: ChannelConfig) -> String {
+        let all = channels.all_inputs();
+        let mut parts = Vec::new();
+        if self.inputs & all == all {
+            parts.push("input".to_string());
+        } else if self.inputs & all != 0 {
+            let inputs: Vec<String> = (0..channels.inputs)
+                .filter(|&channel| self.inputs & (1 << channel) != 0)
+                .map(|channel| (channel + 1).to_string())
+                .collect();
+            parts.push(format!("input {}", inputs.join("+")));
+        }
+        if self.pads {
+            parts.push("pads".to_string());
+        }
+        if self.master {
+            parts.push("master".to_string());
+        }
+        if parts.is_empty() {
+            "nothing".to_string()
+        } else {
+            parts.join(" + ")
+        }
+    }
+}
+
+/// How many input and output channels the looper has.
+///
+/// Internally everything runs on a stereo bus. Even input channels go to the left side and odd
+/// ones to the right side, a single input feeds both sides. Output channels take turns the same
+/// way, a single output gets the mono mixdown.
+///
+/// The main outputs are followed by the [`Self::cue_channels`] outputs of the cue bus, which adds
+/// the metronome for the headphones. With `stems`, these are followed by the outputs of every
+/// loop, each with [`Self::stem_channels`] channels, then by a mono output for the pads and one
+/// for the metronome. The main outputs keep carrying the full mix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelConfig {
+    pub inputs: usize,
+    pub outputs: usize,
+    /// Whether the loops, the pads and the metronome get outputs of their own.
+    pub stems: bool,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        ChannelConfig {
+            inputs: 1,
+            outputs: 1,
+            stems: false,
+        }
+    }
+}
+
+impl ChannelConfig {
+    pub const MAX_CHANNELS: usize = 8;
+    /// How many output ports there can be, stems included.
+    pub const MAX_OUTPUT_PORTS: usize = Self::MAX_CHANNELS + 2 + LOOP_COUNT * 2 + 2;
+
+    /// Read `--inputs <count>`, `--outputs <count>` and `--stems` from the command line
+    /// arguments.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut channels = ChannelConfig::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let count = match arg.as_str() {
+                "--inputs" => &mut channels.inputs,
+                "--outputs" => &mut channels.outputs,
+                "--stems" => {
+                    channels.stems = true;
+                    continue;
+                }
+                _ => bail!("unknown option {}", arg),
+            };
+            *count = args
+                .next()
+                .ok_or_else(|| eyre!("missing value for {}", arg))?
+                .parse()?;
+        }
+        channels.validate()?;
+        Ok(channels)
+    }
+
+    /// Make sure there's at least one and at most [`Self::MAX_CHANNELS`] channels each way.
+    pub fn validate(&self) -> Result<()> {
+        for count in [self.inputs, self.outputs] {
+            if !(1..=Self::MAX_CHANNELS).contains(&count) {
+                bail!(
+                    "there can be 1 to {} input and output channels",
+                    Self::MAX_CHANNELS
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The mask selecting every input channel.
+    pub fn all_inputs(&self) -> InputMask {
+        ALL_INPUTS >> (Self::MAX_CHANNELS - self.inputs)
+    }
+
+    /// Mix the frame at `index` of the input channels selected by `mask` onto the stereo bus.
+    ///
+    /// A single selected channel is put on both sides. Missing input channels are treated as
+    /// silence.
+    pub fn input_frame(&self, inputs: &[&[f32]], index: usize, mask: InputMask) -> Stereo {
+        self.mix_inputs(&self.input_samples(inputs, index), mask)
+    }
+
+    /// The frame at `index` of every input channel, missing input channels are treated as
+    /// silence.
+    pub fn input_samples(&self, inputs: &[&[f32]], index: usize) -> InputFrame {
+        std::array::from_fn(|channel| {
+            inputs
+                .get(channel)
+                .filter(|_| channel < self.inputs)
+                .and_then(|input| input.get(index))
+                .copied()
+                .unwrap_or_default()
+        })
+    }
+
+    /// Mix the input channels of `samples` selected by `mask` onto the stereo bus.
+    ///
+    /// A single selected channel is put on both sides.
+    pub fn mix_inputs(&self, samples: &InputFrame, mask: InputMask) -> Stereo {
+        let mask = mask & self.all_inputs();
+        if mask.count_ones() == 1 {
+            return [samples[mask.trailing_zeros() as usize]; 2];
+        }
+        let mut frame = [0.0; 2];
+        for channel in 0..self.inputs {
+            if mask & (1 << channel) != 0 {
+                frame[channel % 2] += samples[channel];
+            }
+        }
+        frame
+    }
+
+    /// Write a frame of the stereo bus to the frame at `index` of every output channel.
+    pub fn output_frame(&self, outputs: &mut [&mut [f32]], index: usize, frame: Stereo) {
+        let mono = outputs.len() == 1;
+        for (channel, output) in outputs.iter_mut().enumerate() {
+            if let Some(sample) = output.get_mut(index) {
+                *sample = if mono {
+                    (frame[0] + frame[1]) / 2.0
+                } else {
+                    frame[channel % 2]
+                };
+            }
+        }
+    }
+
+    /// How many channels the cue bus has, stereo unless the main output is mono.
+    pub fn cue_channels(&self) -> usize {
+        self.outputs.min(2)
+    }
+
+    /// How many channels the output of each loop has, stereo unless the main output is mono.
+    pub fn stem_channels(&self) -> usize {
+        self.outputs.min(2)
+    }
+
+    /// How many output ports there are, the cue bus and the stems included.
+    pub fn output_port_count(&self) -> usize {
+        let count = self.outputs + self.cue_channels();
+        if self.stems {
+            count + LOOP_COUNT * self.stem_channels() + 2
+        } else {
+            count
+        }
+    }
+
+    /// Write a frame of the cue bus to the frame at `index` of the cue outputs, which follow the
+    /// main outputs in `outputs`.
+    pub fn write_cue(&self, outputs: &mut [&mut [f32]], index: usize, frame: Stereo) {
+        let cue_outputs = self.outputs..self.outputs + self.cue_channels();
+        if let Some(cue_outputs) = outputs.get_mut(cue_outputs) {
+            self.output_frame(cue_outputs, index, frame);
+        }
+    }
+
+    /// The names of the stem output ports, in the order [`Self::write_stems`] expects them.
+    pub fn stem_port_names(&self) -> Vec<String> {
+        if !self.stems {
+            return Vec::new();
+        }
+        let mut names: Vec<String> = (1..=LOOP_COUNT)
+            .flat_map(|index| Self::port_names(&format!("loop{}_out", index), self.stem_channels()))
+            .collect();
+        names.push("pads_out".to_string());
+        names.push("click_out".to_string());
+        names
+    }
+
+    /// Write the frame at `index` of every loop, the pads and the metronome to the stem outputs,
+    /// which follow the cue outputs in `outputs`.
+    pub fn write_stems(
+        &self,
+        outputs: &mut [&mut [f32]],
+        index: usize,
+        loops: &[Stereo],
+        pads: f32,
+        click: f32,
+    ) {
+        let Some(stems) = outputs.get_mut(self.outputs + self.cue_channels()..) else {
+            return;
+        };
+        let stem_channels = self.stem_channels();
+        for (loop_outputs, &frame) in stems.chunks_mut(stem_channels).zip(loops) {
+            self.output_frame(loop_outputs, index, frame);
+        }
+        let buses = stems
+            .get_mut(loops.len() * stem_channels..)
+            .unwrap_or_default();
+        for (output, bus_sample) in buses.iter_mut().zip([pads, click]) {
+            if let Some(sample) = output.get_mut(index) {
+                *sample = bus_sample;
+            }
+        }
+    }
+
+    /// The port names for `count` channels, numbered from 1 when there's more than one.
+    pub fn port_names(prefix: &str, count: usize) -> Vec<String> {
+        if count == 1 {
+            vec![prefix.to_string()]
+        } else {
+            (1..=count)
+                .map(|channel| format!("{}_{}", prefix, channel))
+                .collect()
+        }
+    }
+}
+
+#[test]
+fn test_channel_mapping() {
+    let mono = ChannelConfig::default();
+    assert_eq!(mono.input_frame(&[&[0.5]], 0, ALL_INPUTS), [0.5, 0.5]);
+    let mut output = [0.0];
+    mono.output_frame(&mut [&mut output], 0, [0.5, 0.25]);
+    assert_eq!(output, [0.375]);
+
+    let quad = ChannelConfig {
+        inputs: 4,
+        outputs: 4,
+        stems: false,
+    };
+    let inputs: [&[f32]; 4] = [&[0.1], &[0.2], &[0.3], &[0.4]];
+    assert_eq!(
+        quad.input_frame(&inputs, 0, ALL_INPUTS),
+        [0.1 + 0.3, 0.2 + 0.4]
+    );
+    assert_eq!(quad.input_frame(&inputs, 0, 0b0010), [0.2, 0.2]);
+    assert_eq!(quad.input_frame(&inputs, 0, 0b1100), [0.3, 0.4]);
+    let mut outputs = [[0.0]; 4];
+    let [a, b, c, d] = &mut outputs;
+    quad.output_frame(&mut [a, b, c, d], 0, [0.5, -0.5]);
+    assert_eq!(outputs, [[0.5], [-0.5], [0.5], [-0.5]]);
+
+    assert_eq!(
+        ChannelConfig::port_names("loooper_in", 2),
+        vec!["loooper_in_1", "loooper_in_2"]
+    );
+    assert_eq!(
+        ChannelConfig::from_args(&["--inputs".into(), "2".into()]).unwrap(),
+        ChannelConfig {
+            inputs: 2,
+            outputs: 1,
+            stems: false,
+        }
+    );
+    assert!(ChannelConfig::from_args(&["--stems".into()]).unwrap().stems);
+    assert!(ChannelConfig::from_args(&["--outputs".into(), "0".into()]).is_err());
+}
+
+#[test]
+fn test_loop_source() {
+    let quad = ChannelConfig {
+        inputs: 4,
+        outputs: 2,
+        stems: false,
+    };
+    let mut source = LoopSource::default();
+    assert_eq!(source.describe(quad), "input + pads");
+    let mut choices = Vec::new();
+    for _ in 0..8 {
+        source.cycle_inputs(quad);
+        choices.push(source.inputs);
+    }
+    assert_eq!(
+        choices,
+        vec![0b0001, 0b0010, 0b0100, 0b1000, 0b0011, 0b1100, 0, 0b1111]
+    );
+    source.inputs = 0b1100;
+    source.pads = false;
+    source.master = true;
+    assert_eq!(source.describe(quad), "input 3+4 + master");
+
+    assert_eq!(source.channels(quad), 2);
+    source.master = false;
+    assert_eq!(source.channels(quad), 2);
+    source.inputs = 0b0100;
+    assert_eq!(source.channels(quad), 1);
+    assert_eq!(LoopSource::default().channels(ChannelConfig::default()), 1);
+
+    let mut mono_source = LoopSource::default();
+    mono_source.cycle_inputs(ChannelConfig::default());
+    assert_eq!(mono_source.inputs, 0);
+}
+
+#[test]
+fn test_stems() {
+    let channels = ChannelConfig {
+        inputs: 1,
+        outputs: 2,
+        stems: true,
+    };
+    let names = channels.stem_port_names();
+    assert_eq!(channels.output_port_count(), 2 + 2 + names.len());
+    assert_eq!(names[..2], ["loop1_out_1", "loop1_out_2"]);
+    assert_eq!(names[names.len() - 2..], ["pads_out", "click_out"]);
+
+    let mut outputs = vec![[0.0]; channels.output_port_count()];
+    let mut slices: Vec<&mut [f32]> = outputs.iter_mut().map(|output| &mut output[..]).collect();
+    let mut loops = [[0.0; 2]; LOOP_COUNT];
+    loops[1] = [0.5, -0.5];
+    channels.write_stems(&mut slices, 0, &loops, 0.25, 0.125);
+    assert_eq!(outputs[..6], [[0.0]; 6]);
+    assert_eq!(outputs[6..8], [[0.5], [-0.5]]);
+    assert_eq!(outputs[outputs.len() - 2..], [[0.25], [0.125]]);
+}
@@ -0,0 +1,152 @@
+/// An exact sample clock for the beats of the [`AudioEngine`](super::AudioEngine).
+///
+/// A beat lasts `samples / beats` samples, kept as a fraction instead of being rounded, so beat
+/// `n` starts at exactly `ceil(n * samples / beats)` samples after the clock was started. Beats
+/// never drift against each other or against the loop buffers, whatever the tempo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct BeatClock {
+    samples: u64,
+    beats: u64,
+    /// The current sample, counting from 0 when the clock started
+    sample: u64,
+}
+
+impl BeatClock {
+    /// A clock running at `mbpm` (BPM * 1000).
+    pub fn from_tempo(sample_rate: usize, mbpm: u32) -> Self {
+        Self::from_beat_length(sample_rate as u64 * 60_000, mbpm.max(1) as u64)
+    }
+
+    /// A clock where `beats` beats last exactly `samples` samples.
+    pub fn from_beat_length(samples: u64, beats: u64) -> Self {
+        BeatClock {
+            samples: samples.max(1),
+            beats: beats.max(1),
+            sample: 0,
+        }
+    }
+
+    /// The tempo in BPM * 1000, rounded.
+    pub fn mbpm(&self, sample_rate: usize) -> u32 {
+        let numerator = sample_rate as u128 * 60_000 * self.beats as u128;
+        let samples = self.samples as u128;
+        ((numerator + samples / 2) / samples) as u32
+    }
+
+    /// Whether beats last just as long on both clocks.
+    pub fn same_tempo(&self, other: &BeatClock) -> bool {
+        self.samples as u128 * other.beats as u128 == other.samples as u128 * self.beats as u128
+    }
+
+    /// How many times as long a beat lasts on this clock as on `other`.
+    pub fn beat_length_ratio(&self, other: &BeatClock) -> f64 {
+        (self.samples as f64 * other.beats as f64) / (self.beats as f64 * other.samples as f64)
+    }
+
+    /// Start counting from the current sample, which becomes the start of beat 0.
+    pub fn restart(&mut self) {
+        self.sample = 0;
+    }
+
+    /// Move on to the next sample.
+    pub fn advance(&mut self) {
+        self.sample += 1;
+    }
+
+    /// The current sample, counting from the start of beat 0.
+    pub fn sample(&self) -> u64 {
+        self.sample
+    }
+
+    /// How many `1 / divisions` of a beat fully passed before the current sample.
+    pub fn position(&self, divisions: u64) -> u64 {
+        (self.scaled(divisions) / self.samples as u128) as u64
+    }
+
+    /// The beat the current sample is in, counting from 0.
+    pub fn beat(&self) -> u64 {
+        self.position(1)
+    }
+
+    /// Whether the current sample is the first of a beat.
+    pub fn is_beat_start(&self) -> bool {
+        self.division_fraction(1) < self.beats as u128
+    }
+
+    /// Where the current sample is inside its `1 / divisions` of a beat, from 0.0 to 1.0.
+    pub fn fraction(&self, divisions: u64) -> f32 {
+        self.division_fraction(divisions) as f32 / self.samples as f32
+    }
+
+    /// The sample beat `beat` starts at.
+    pub fn beat_start(&self, beat: u64) -> u64 {
+        (beat as u128 * self.samples as u128).div_ceil(self.beats as u128) as u64
+    }
+
+    /// The most samples `beats` beats last, wherever they start.
+    pub fn samples_in(&self, beats: u64) -> u64 {
+        // The beats starting at beat 0 are never shorter than any others
+        self.beat_start(beats)
+    }
+
+    /// The current sample scaled so a `1 / divisions` of a beat lasts `samples`.
+    fn scaled(&self, divisions: u64) -> u128 {
+        self.sample as u128 * self.beats as u128 * divisions as u128
+    }
+
+    /// How far the current sample is into its `1 / divisions` of a beat, in `1 / samples`.
+    fn division_fraction(&self, divisions: u64) -> u128 {
+        self.scaled(divisions) % self.samples as u128
+    }
+}
+
+/// How many samples a beat lasts at `mbpm` (BPM * 1000), rounded up.
+pub(super) fn samples_per_beat(sample_rate: usize, mbpm: u32) -> u64 {
+    BeatClock::from_tempo(sample_rate, mbpm).samples_in(1)
+}
+
+#[test]
+fn test_beat_clock() {
+    // 113.33 BPM doesn't divide 4800 Hz evenly
+    let mut clock = BeatClock::from_tempo(4800, 113_330);
+    let mut starts = Vec::new();
+    for _ in 0..1_000_000 {
+        if clock.is_beat_start() {
+            assert_eq!(clock.beat_start(clock.beat()), clock.sample());
+            assert_eq!(clock.position(1000) % 1000, 0);
+            starts.push(clock.sample());
+        }
+        clock.advance();
+    }
+    // No drift: the last beat starts exactly where the tempo puts it
+    let beats = starts.len() as u64 - 1;
+    assert_eq!(starts[0], 0);
+    assert_eq!(
+        starts.last(),
+        Some(&(beats * 4800 * 60_000).div_ceil(113_330))
+    );
+    assert!(
+        starts
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] <= clock.samples_in(1))
+    );
+
+    // A clock derived from a recorded loop keeps its exact length
+    let mut clock = BeatClock::from_beat_length(10_001, 4);
+    assert_eq!(clock.beat_start(4), 10_001);
+    assert_eq!(clock.samples_in(1), 2501);
+    assert_eq!(clock.mbpm(4800), 115_188);
+    for _ in 0..2501 {
+        clock.advance();
+    }
+    assert!(clock.is_beat_start());
+    assert_eq!(clock.beat(), 1);
+    clock.restart();
+    assert!(clock.is_beat_start() && clock.fraction(1) == 0.0);
+
+    let slow = BeatClock::from_tempo(4800, 60_000);
+    let fast = BeatClock::from_tempo(4800, 120_000);
+    assert!(fast.same_tempo(&BeatClock::from_beat_length(2400, 1)));
+    assert!(!fast.same_tempo(&slow));
+    assert_eq!(slow.beat_length_ratio(&fast), 2.0);
+}
@@ -0,0 +1,2160 @@
+use super::channels::{ALL_INPUTS, ChannelConfig, InputFrame, LoopSource, Stereo};
+use super::clock::{BeatClock, samples_per_beat};
+use super::meter::{Meter, MeterSource, Meters};
+use super::metronome::{Click, ClickMute, ClickPhase, MetronomeConfig, Tick};
+use super::mix::{Level, Levels, LoopMix, LoopSpeed};
+use super::pool::LoopBuffer;
+use super::protocol::{EngineCommand, EngineEvent};
+use super::queue::{QueueReceiver, QueueSender};
+use super::sample::SamplePad;
+use super::stretch::Stretcher;
+use super::time_signature::TimeSignature;
+use crate::filter::{
+    AutoTune, AutoTuneConfig, Delay, Distortion, Filter, Limiter, PitchShift, Transpose, Wah,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// How many loops the engine can hold.
+pub const LOOP_COUNT: usize = 8;
+const MAX_ACTIVE_PADS: usize = 8;
+/// How far the limiters of the buses look ahead, which delays the buses as well
+const LIMITER_LOOKAHEAD_MS: usize = 2;
+const LIMITER_CEILING: f32 = 0.98;
+const LIMITER_RELEASE_MS: usize = 100;
+/// How long it takes a loop gain change to get most of the way, to avoid zipper noise
+const GAIN_SMOOTHING_MS: f32 = 10.0;
+/// How long a loop crossfades between its directions when it's reversed
+const REVERSE_FADE_MS: usize = 10;
+
+pub struct AudioEngineSettings {
+    pub sample_rate: usize,
+    pub channels: ChannelConfig,
+    pub commands: QueueReceiver<EngineCommand>,
+    pub events: QueueSender<EngineEvent>,
+    /// New loop buffers, see [`LoopBufferPool`](super::LoopBufferPool).
+    pub buffers: QueueReceiver<LoopBuffer>,
+    /// Where replaced loop buffers are sent to be freed.
+    pub returned_buffers: QueueSender<Box<[f32]>>,
+    /// New samples for the metronome click, loaded on the main thread.
+    pub click_samples: QueueReceiver<SamplePad>,
+    /// Where replaced click samples are sent to be freed.
+    pub returned_samples: QueueSender<SamplePad>,
+    pub current_millibeat: Arc<AtomicU32>,
+    /// Where the levels are published.
+    pub meters: Arc<Meters>,
+    /// How many xruns JACK reported so far.
+    pub xruns: Arc<AtomicU32>,
+}
+
+/// The looper itself: the clock, the metronome, the loops, the pads and the filters.
+///
+/// The engine knows nothing about JACK, it just turns blocks of input samples into blocks of
+/// output samples, so it can be driven by the JACK process callback as well as by tests or an
+/// offline renderer.
+///
+/// The engine is controlled with [`EngineCommand`]s and reports back with [`EngineEvent`]s.
+/// Commands changing the transport or the loops are applied at beat or loop boundaries.
+pub struct AudioEngine {
+    sample_rate: usize,
+    channels: ChannelConfig,
+    commands: QueueReceiver<EngineCommand>,
+    events: QueueSender<EngineEvent>,
+    buffers: QueueReceiver<LoopBuffer>,
+    returned_buffers: QueueSender<Box<[f32]>>,
+    click_samples: QueueReceiver<SamplePad>,
+    returned_samples: QueueSender<SamplePad>,
+    current_millibeat: Arc<AtomicU32>,
+    meters: Arc<Meters>,
+    xruns: Arc<AtomicU32>,
+    last_xruns: u32,
+    input_meter: Meter,
+    loop_meters: [Meter; LOOP_COUNT],
+    master_meter: Meter,
+    meter_smoothing: f32,
+
+    enabled: bool,
+    mbpm: u32,
+    pending_mbpm: Option<u32>,
+    /// The tempo a ramp is heading to and in how many beats it gets there
+    tempo_ramp: Option<(u32, u32)>,
+    time_signature: TimeSignature,
+    levels: Levels,
+    clock: BeatClock,
+    pending_countin: Option<u32>,
+    /// The loop being recorded to define the tempo, if any
+    free_recording: Option<usize>,
+    countin_started: bool,
+    countin_left: u32,
+    /// Whether loops starting with the rolling phase record the end of the count-in as well
+    pre_roll: bool,
+    rolling: bool,
+    metronome: MetronomeConfig,
+    click: Click,
+    /// The volume of the current beat's clicks, zero if the metronome is muted
+    click_volume: f32,
+    /// Which click of the current beat was played last, for the subdivisions
+    click_tick: u32,
+    current_beat: u32, // Which beat we're in, start at beat 1, including the count-in
+
+    loop_length: [u32; LOOP_COUNT],
+    loop_layering: [bool; LOOP_COUNT],
+    loop_source: [LoopSource; LOOP_COUNT],
+    /// Whether the loop should play (or record first) from its next boundary on
+    loop_armed: [bool; LOOP_COUNT],
+    /// Whether the loop should record a new pass from its next boundary on
+    loop_recording_armed: [bool; LOOP_COUNT],
+    loop_clearing: [bool; LOOP_COUNT],
+    loop_buffers: Vec<Box<[f32]>>,
+    loop_filled: [bool; LOOP_COUNT],
+    loop_looping: [bool; LOOP_COUNT],
+    loop_capturing: [bool; LOOP_COUNT],
+    /// Whether the current capture pass is mixed into the existing content instead of replacing it
+    loop_overdubbing: [bool; LOOP_COUNT],
+    loop_pos: [usize; LOOP_COUNT],
+    /// The tempo each loop was recorded at
+    loop_clock: [BeatClock; LOOP_COUNT],
+    /// How many channels the buffer of each loop interleaves
+    loop_channels: [usize; LOOP_COUNT],
+    /// How many frames a pass of each loop lasted when it was recorded
+    loop_content: [usize; LOOP_COUNT],
+    /// Plays the loops recorded at another tempo
+    stretchers: [Stretcher; LOOP_COUNT],
+    loop_speed: [LoopSpeed; LOOP_COUNT],
+    /// Where the loops played at another speed are in their content, in frames
+    loop_read: [f64; LOOP_COUNT],
+    loop_reversed: [bool; LOOP_COUNT],
+    /// How far each loop is into playing backwards, from 0.0 to 1.0, crossfading at a switch
+    loop_reverse_fade: [f32; LOOP_COUNT],
+    /// How much of the crossfade between the directions is taken each sample
+    reverse_fade_step: f32,
+    /// The last frame each loop played, for the stem outputs
+    loop_output: [Stereo; LOOP_COUNT],
+    loop_mix: [LoopMix; LOOP_COUNT],
+    /// The gain of each side of each loop the mixer is heading to
+    loop_gain_target: [Stereo; LOOP_COUNT],
+    /// The gain of each side of each loop, following the target smoothly
+    loop_gain: [Stereo; LOOP_COUNT],
+    /// How much of the way to the target gain is taken each sample
+    gain_smoothing: f32,
+    /// The beat the current pass of each loop started at
+    loop_recording_start_beat: [u32; LOOP_COUNT],
+    /// Where the end of the count-in was recorded into a loop, the first pass is mixed into it
+    loop_pre_roll: [Option<usize>; LOOP_COUNT],
+
+    monitor_delay: [Delay; 2],
+    monitor_pitch: [PitchShift; 2],
+    auto_tune: AutoTune,
+    auto_tune_config: AutoTuneConfig,
+    /// The previous frame of the main bus before its level, for resampling it
+    master_frame: Stereo,
+    playback_delay: Vec<[Delay; 2]>,
+    playback_pitch: Vec<[PitchShift; 2]>,
+    main_limiter: [Limiter; 2],
+    cue_limiter: [Limiter; 2],
+    distortion: Distortion,
+    _wah: Wah,
+
+    pads: Box<[SamplePad]>,
+    active_pads: [Option<SamplePad>; MAX_ACTIVE_PADS],
+    active_pad_count: usize,
+}
+
+impl AudioEngine {
+    pub fn new(settings: AudioEngineSettings) -> Self {
+        let AudioEngineSettings {
+            sample_rate,
+            channels,
+            commands,
+            events,
+            buffers,
+            returned_buffers,
+            click_samples,
+            returned_samples,
+            current_millibeat,
+            meters,
+            xruns,
+        } = settings;
+
+        // The buffers are handed over by the pool once the loop lengths are known
+        let loop_buffers = (0..LOOP_COUNT).map(|_| Box::default()).collect::<Vec<_>>();
+
+        const DELAY_MS: usize = 250;
+        const FEEDBACK: f32 = 0.4;
+        const WET: f32 = 0.8;
+        let delay_samples = (sample_rate * DELAY_MS) / 1000;
+
+        let load_sound = |file: &str| {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("src")
+                .join("sounds")
+                .join(file);
+            SamplePad::load_from_wav(&path).unwrap_or_default()
+        };
+        let pad_files = ["pad1.wav", "pad2.wav", "pad3.wav", "pad4.wav"];
+        let pads: Box<[SamplePad]> = pad_files.iter().map(|file| load_sound(file)).collect();
+
+        let mbpm = 120000;
+        AudioEngine {
+            sample_rate,
+            channels,
+            commands,
+            events,
+            buffers,
+            returned_buffers,
+            click_samples,
+            returned_samples,
+            current_millibeat,
+            meters,
+            last_xruns: xruns.load(Ordering::Relaxed),
+            xruns,
+            input_meter: Meter::default(),
+            loop_meters: [Meter::default(); LOOP_COUNT],
+            master_meter: Meter::default(),
+            meter_smoothing: Meter::smoothing(sample_rate),
+
+            enabled: false,
+            mbpm,
+            pending_mbpm: None,
+            tempo_ramp: None,
+            time_signature: TimeSignature::default(),
+            levels: Levels::default(),
+            clock: BeatClock::from_tempo(sample_rate, mbpm),
+            pending_countin: None,
+            free_recording: None,
+            countin_started: false,
+            countin_left: 0,
+            pre_roll: false,
+            rolling: false,
+            metronome: MetronomeConfig::default(),
+            click: Click::new(sample_rate, load_sound("click.wav")),
+            click_volume: 0.0,
+            click_tick: 0,
+            current_beat: 0,
+
+            loop_length: [4; LOOP_COUNT],
+            loop_layering: [false; LOOP_COUNT],
+            loop_source: [LoopSource::default(); LOOP_COUNT],
+            loop_armed: [false; LOOP_COUNT],
+            loop_recording_armed: [false; LOOP_COUNT],
+            loop_clearing: [false; LOOP_COUNT],
+            loop_buffers,
+            loop_filled: [false; LOOP_COUNT],
+            loop_looping: [false; LOOP_COUNT],
+            loop_capturing: [false; LOOP_COUNT],
+            loop_overdubbing: [false; LOOP_COUNT],
+            loop_pos: [0; LOOP_COUNT],
+            loop_clock: [BeatClock::from_tempo(sample_rate, mbpm); LOOP_COUNT],
+            loop_channels: [1; LOOP_COUNT],
+            loop_content: [0; LOOP_COUNT],
+            stretchers: std::array::from_fn(|_| Stretcher::new(sample_rate)),
+            loop_speed: [LoopSpeed::NORMAL; LOOP_COUNT],
+            loop_read: [0.0; LOOP_COUNT],
+            loop_reversed: [false; LOOP_COUNT],
+            loop_reverse_fade: [0.0; LOOP_COUNT],
+            reverse_fade_step: 1.0 / (sample_rate * REVERSE_FADE_MS / 1000).max(1) as f32,
+            loop_output: [[0.0; 2]; LOOP_COUNT],
+            loop_mix: [LoopMix::default(); LOOP_COUNT],
+            loop_gain_target: [[1.0; 2]; LOOP_COUNT],
+            loop_gain: [[1.0; 2]; LOOP_COUNT],
+            gain_smoothing: 1.0 - (-1000.0 / (GAIN_SMOOTHING_MS * sample_rate as f32)).exp(),
+            loop_recording_start_beat: [0; LOOP_COUNT],
+            loop_pre_roll: [None; LOOP_COUNT],
+
+            monitor_delay: std::array::from_fn(|_| Delay::new(delay_samples, FEEDBACK, WET)),
+            monitor_pitch: std::array::from_fn(|_| PitchShift::new(sample_rate)),
+            auto_tune: AutoTune::new(sample_rate),
+            auto_tune_config: AutoTuneConfig::default(),
+            master_frame: [0.0; 2],
+            main_limiter: std::array::from_fn(|_| limiter(sample_rate)),
+            cue_limiter: std::array::from_fn(|_| limiter(sample_rate)),
+            playback_delay: vec![
+                std::array::from_fn(|_| Delay::new(delay_samples, FEEDBACK, WET));
+                LOOP_COUNT
+            ],
+            playback_pitch: vec![std::array::from_fn(|_| PitchShift::new(sample_rate)); LOOP_COUNT],
+            distortion: Distortion::new(8.0, 0.5),
+            _wah: Wah::new(
+                sample_rate as f32,
+                2.0,    // sweep at 2 Hz
+                500.0,  // min 500 Hz
+                3000.0, // max 3 kHz
+                0.8,    // resonance
+            ),
+
+            pads,
+            active_pads: [const { None }; MAX_ACTIVE_PADS],
+            active_pad_count: 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> ChannelConfig {
+        self.channels
+    }
+
+    /// Process a block of audio.
+    ///
+    /// There's a slice for each input and output channel, which are all expected to have the
+    /// same length. The cue and stem outputs follow the main outputs, see [`ChannelConfig`].
+    /// This function SHOULD NOT panic, nor should it allocate memory or perform any
+    /// other potentially blocking operations.
+    pub fn process(&mut self, inputs: &[&[f32]], outputs: &mut [&mut [f32]]) {
+        let block_length = outputs.first().map_or(0, |output| output.len());
+        debug_assert!(inputs.iter().all(|input| input.len() == block_length));
+
+        // Take new buffers first, so the commands about their loops can use them
+        self.receive_buffers();
+        self.receive_click_samples();
+        self.receive_commands();
+
+        let xruns = self.xruns.load(Ordering::Relaxed);
+        if xruns != self.last_xruns {
+            self.last_xruns = xruns;
+            self.emit(EngineEvent::Xrun);
+        }
+
+        // We're not enabled, output nothing and quit callback
+        if !self.enabled {
+            for output in outputs.iter_mut() {
+                output.fill(0.0);
+            }
+            self.input_meter = Meter::default();
+            self.loop_meters = [Meter::default(); LOOP_COUNT];
+            self.master_meter = Meter::default();
+            self.publish_meters();
+            return;
+        }
+
+        for frame in 0..block_length {
+            let dry_inputs = self.channels.input_samples(inputs, frame);
+            self.input_meter.add(
+                self.channels.mix_inputs(&dry_inputs, ALL_INPUTS),
+                self.meter_smoothing,
+            );
+            let tuned_inputs = self.tune_inputs(dry_inputs);
+            let in_frame = self.channels.mix_inputs(&tuned_inputs, ALL_INPUTS);
+
+            // Monitor the input
+            let mut monitor_frame = [0.0; 2];
+            for (channel, monitor_sample) in monitor_frame.iter_mut().enumerate() {
+                let temp_sample = self.distortion.apply(in_frame[channel]);
+                let temp_sample = self.monitor_pitch[channel].apply(temp_sample);
+                *monitor_sample = self.monitor_delay[channel].apply(temp_sample);
+            }
+
+            if let Some(index) = self.free_recording
+                && self.loop_pos[index] >= self.loop_frames(index)
+            {
+                // The loop buffer is full, we have to stop here. This happens before the
+                // clock runs, so the restarted clock starts rolling with this very sample
+                self.finish_free_recording();
+            }
+
+            // There's no tempo yet while the first loop is recorded freely
+            let click = if self.free_recording.is_none() {
+                self.process_clock()
+            } else {
+                0.0
+            };
+
+            let pad_mix = self.next_pad_mix();
+
+            let captured_inputs = if self.auto_tune_config.capture {
+                &tuned_inputs
+            } else {
+                &dry_inputs
+            };
+            let loop_mix = self.process_loops(captured_inputs, pad_mix);
+            let mut main_frame = [0.0; 2];
+            let mut cue_frame = [0.0; 2];
+            for channel in 0..2 {
+                let playback = pad_mix + loop_mix[channel];
+                // The master mix is resampled before the levels apply
+                self.master_frame[channel] = monitor_frame[channel] + playback;
+                main_frame[channel] = self.master_frame[channel] * self.levels.gain(Level::Main);
+                cue_frame[channel] = click * self.levels.gain(Level::CueClick)
+                    + monitor_frame[channel] * self.levels.gain(Level::CueMonitor)
+                    + playback * self.levels.gain(Level::CueLoops);
+            }
+            for channel in 0..2 {
+                main_frame[channel] = self.main_limiter[channel].apply(main_frame[channel]);
+                cue_frame[channel] = self.cue_limiter[channel].apply(cue_frame[channel]);
+            }
+            self.master_meter.add(main_frame, self.meter_smoothing);
+            let main_outputs = self.channels.outputs.min(outputs.len());
+            self.channels
+                .output_frame(&mut outputs[..main_outputs], frame, main_frame);
+            self.channels.write_cue(outputs, frame, cue_frame);
+            if self.channels.stems {
+                self.channels
+                    .write_stems(outputs, frame, &self.loop_output, pad_mix, click);
+            }
+
+            self.clock.advance();
+        }
+
+        self.publish_meters();
+
+        let overs = self
+            .main_limiter
+            .iter_mut()
+            .chain(&mut self.cue_limiter)
+            .map(Limiter::take_overs)
+            .sum();
+        if overs > 0 {
+            self.emit(EngineEvent::Clipped(overs));
+        }
+    }
+
+    fn publish_meters(&mut self) {
+        self.input_meter.publish(&self.meters, MeterSource::Input);
+        for (index, meter) in self.loop_meters.iter_mut().enumerate() {
+            meter.publish(&self.meters, MeterSource::Loop(index));
+        }
+        self.master_meter.publish(&self.meters, MeterSource::Master);
+    }
+
+    /// How many samples the main and cue buses are delayed by their limiters
+    pub fn output_latency(&self) -> usize {
+        self.main_limiter[0].latency()
+    }
+
+    /// Advance the beat clock for a single sample, returning the metronome click
+    fn process_clock(&mut self) -> f32 {
+        // We entered a new beat
+        if self.clock.is_beat_start() {
+            if let Some((target, beats)) = self.tempo_ramp {
+                // Take an even step towards the target at every beat
+                let mbpm = self.pending_mbpm.unwrap_or(self.mbpm) as i64;
+                let mbpm = (mbpm + (target as i64 - mbpm) / beats.max(1) as i64) as u32;
+                self.tempo_ramp = (beats > 1).then_some((target, beats - 1));
+                self.pending_mbpm = Some(if beats > 1 { mbpm } else { target });
+                self.emit(EngineEvent::TempoChanged(self.pending_mbpm.unwrap_or(mbpm)));
+            }
+            if let Some(mbpm) = self.pending_mbpm.take() {
+                // We're at the start of a beat, so restarting the clock keeps us in phase
+                self.mbpm = mbpm;
+                self.clock = BeatClock::from_tempo(self.sample_rate, mbpm);
+            }
+
+            // Check if Count-in just started
+            if let Some(countin_length) = self.pending_countin.take() {
+                // Reset the audio clock
+                self.clock.restart();
+                // Reset the beat counters
+                self.current_beat = 0;
+                self.current_millibeat.store(1000, Ordering::Relaxed);
+
+                // Set up the countin flags
+                self.countin_left = countin_length;
+                self.countin_started = true;
+            }
+
+            self.next_beat();
+        }
+
+        let current_subbeat = (self.clock.position(1000) % 1000) as u32;
+        self.current_millibeat.store(
+            self.current_beat * 1000 + current_subbeat,
+            Ordering::Relaxed,
+        );
+
+        // Click the subdivisions of the beat as well
+        let ticks = self.metronome.subdivision.ticks_per_beat() as u64;
+        let tick = (self.clock.position(ticks) % ticks) as u32;
+        if tick > self.click_tick {
+            self.click_tick = tick;
+            self.click.trigger(Tick::Subdivision, self.click_volume);
+        }
+
+        // Set the click to release state after a quarter of its tick
+        if self.clock.fraction(ticks) > 0.25 {
+            self.click.release();
+        }
+        self.click.next_sample()
+    }
+
+    fn emit(&mut self, event: EngineEvent) {
+        // Dropped events are counted by the queue and reported by the main thread
+        let _ = self.events.send(event);
+    }
+
+    /// Apply or schedule the commands sent by the main thread
+    fn receive_commands(&mut self) {
+        while let Some(command) = self.commands.try_recv() {
+            match command {
+                EngineCommand::Enable => {
+                    if !self.enabled {
+                        // We just got enabled, reset relavent audio callback states
+                        self.enabled = true;
+                        self.clock.restart();
+                    }
+                }
+                EngineCommand::Disable => self.reset(),
+                EngineCommand::SetTempo(mbpm) => {
+                    if mbpm > 0 {
+                        self.pending_mbpm = Some(mbpm);
+                        self.tempo_ramp = None;
+                    }
+                }
+                EngineCommand::RampTempo { mbpm, beats } => {
+                    if mbpm > 0 && beats > 0 {
+                        self.tempo_ramp = Some((mbpm, beats));
+                    }
+                }
+                EngineCommand::SetTimeSignature(time_signature) => {
+                    if time_signature.beats_per_bar > 0 {
+                        self.time_signature = time_signature;
+                    }
+                }
+                EngineCommand::SetMetronome(metronome) => {
+                    self.metronome = metronome;
+                    self.click.set_sound(metronome.sound);
+                }
+                EngineCommand::SetLevel(level, percent) => self.levels.set(level, percent),
+                EngineCommand::StartCountIn(length) => self.pending_countin = Some(length),
+                EngineCommand::SetPreRoll(pre_roll) => self.pre_roll = pre_roll,
+                EngineCommand::StartFreeRecording(index) => self.start_free_recording(index),
+                EngineCommand::FinishFreeRecording => self.finish_free_recording(),
+                EngineCommand::ConfigureLoop {
+                    index,
+                    length,
+                    layering,
+                } => {
+                    if index < LOOP_COUNT {
+                        self.loop_length[index] = length;
+                        self.loop_layering[index] = layering;
+                    }
+                }
+                EngineCommand::SetLoopSource { index, source } => {
+                    if index < LOOP_COUNT {
+                        self.loop_source[index] = source;
+                    }
+                }
+                EngineCommand::SetLoopMix { index, mix } => {
+                    if index < LOOP_COUNT {
+                        self.loop_mix[index] = mix;
+                        self.update_loop_gains();
+                    }
+                }
+                EngineCommand::SetAutoTune(config) => {
+                    self.auto_tune_config = config;
+                    self.auto_tune.set_config(&config);
+                }
+                EngineCommand::SetMonitorTranspose(transpose) => {
+                    for pitch_shift in &mut self.monitor_pitch {
+                        pitch_shift.set_transpose(transpose);
+                    }
+                }
+                EngineCommand::SetLoopTranspose { index, transpose } => {
+                    if let Some(pitch_shifts) = self.playback_pitch.get_mut(index) {
+                        for pitch_shift in pitch_shifts {
+                            pitch_shift.set_transpose(transpose);
+                        }
+                    }
+                }
+                EngineCommand::SetLoopSpeed { index, speed } => {
+                    if index < LOOP_COUNT && speed.percent() > 0 {
+                        self.loop_speed[index] = speed;
+                    }
+                }
+                EngineCommand::SetLoopReverse { index, reversed } => {
+                    if index < LOOP_COUNT {
+                        self.loop_reversed[index] = reversed;
+                    }
+                }
+                EngineCommand::ArmLoop(index) => {
+                    if index < LOOP_COUNT {
+                        self.loop_armed[index] = true;
+                    }
+                }
+                EngineCommand::StopLoop(index) => {
+                    if index < LOOP_COUNT {
+                        self.loop_armed[index] = false;
+                    }
+                }
+                EngineCommand::RecordLoop(index) => {
+                    if index < LOOP_COUNT {
+                        self.loop_recording_armed[index] = true;
+                    }
+                }
+                EngineCommand::CancelRecording(index) => {
+                    if index < LOOP_COUNT {
+                        self.loop_recording_armed[index] = false;
+                    }
+                }
+                EngineCommand::ClearLoop(index) => {
+                    if index < LOOP_COUNT {
+                        self.loop_clearing[index] = true;
+                    }
+                }
+                EngineCommand::TriggerPad(index) => self.start_pad(index),
+            }
+        }
+    }
+
+    /// Swap in the loop buffers sent by the pool, sending the old ones back to be freed
+    fn receive_buffers(&mut self) {
+        // Dropping a buffer here would free memory on the audio thread
+        while !self.returned_buffers.is_full() {
+            let Some(LoopBuffer {
+                index,
+                channels,
+                samples,
+            }) = self.buffers.try_recv()
+            else {
+                break;
+            };
+            if index >= LOOP_COUNT {
+                continue;
+            }
+            let old_buffer = std::mem::replace(&mut self.loop_buffers[index], samples);
+            self.loop_channels[index] = channels.max(1);
+            let _ = self.returned_buffers.send(old_buffer);
+            if self.loop_filled[index] || self.loop_capturing[index] {
+                // Whatever was recorded is gone with the old buffer
+                if self.free_recording == Some(index) {
+                    self.free_recording = None;
+                }
+                self.loop_filled[index] = false;
+                self.loop_capturing[index] = false;
+                self.loop_overdubbing[index] = false;
+                self.loop_looping[index] = false;
+                self.emit(EngineEvent::LoopCleared(index));
+            }
+        }
+    }
+
+    /// Swap in the click samples loaded by the main thread, sending the old ones back to be freed
+    fn receive_click_samples(&mut self) {
+        while !self.returned_samples.is_full() {
+            let Some(sample) = self.click_samples.try_recv() else {
+                break;
+            };
+            let old_sample = self.click.replace_sample(sample);
+            let _ = self.returned_samples.send(old_sample);
+        }
+    }
+
+    /// Stop the transport and forget about every loop
+    fn reset(&mut self) {
+        self.enabled = false;
+        self.pending_countin = None;
+        self.tempo_ramp = None;
+        self.free_recording = None;
+        self.countin_started = false;
+        self.rolling = false;
+        self.current_beat = 0;
+        self.current_millibeat.store(0, Ordering::Relaxed);
+        self.loop_armed = [false; LOOP_COUNT];
+        self.loop_recording_armed = [false; LOOP_COUNT];
+        self.loop_clearing = [false; LOOP_COUNT];
+        self.loop_filled = [false; LOOP_COUNT];
+        self.loop_looping = [false; LOOP_COUNT];
+        self.loop_capturing = [false; LOOP_COUNT];
+        self.loop_overdubbing = [false; LOOP_COUNT];
+        self.loop_pre_roll = [None; LOOP_COUNT];
+        // A solo left over from the last session would mute the loops of the next one
+        self.loop_mix = [LoopMix::default(); LOOP_COUNT];
+        self.update_loop_gains();
+        self.loop_gain = self.loop_gain_target;
+        // The same goes for the playback settings, the next session starts from the defaults
+        self.loop_speed = [LoopSpeed::NORMAL; LOOP_COUNT];
+        self.loop_reversed = [false; LOOP_COUNT];
+        self.loop_reverse_fade = [0.0; LOOP_COUNT];
+        for pitch_shift in self.playback_pitch.iter_mut().flatten() {
+            pitch_shift.set_transpose(Transpose::default());
+        }
+        self.master_frame = [0.0; 2];
+    }
+
+    /// Start recording a loop which defines the tempo once it's finished
+    fn start_free_recording(&mut self, index: usize) {
+        if index >= LOOP_COUNT
+            || self.loop_buffers[index].is_empty()
+            || !self.enabled
+            || self.rolling
+            || self.countin_started
+        {
+            return;
+        }
+        self.pending_countin = None;
+        self.free_recording = Some(index);
+        self.current_beat = 0;
+        self.current_millibeat.store(0, Ordering::Relaxed);
+        self.loop_filled[index] = false;
+        self.start_capture(index);
+    }
+
+    /// Derive the tempo from the freely recorded loop and start rolling with it
+    fn finish_free_recording(&mut self) {
+        let Some(index) = self.free_recording else {
+            return;
+        };
+        let beats = self.loop_length[index].max(1) as u64;
+        let samples = self.loop_pos[index] as u64;
+        self.free_recording = None;
+        if samples / beats < samples_per_beat_at_max_tempo(self.sample_rate) {
+            // Way too short to be intended
+            self.loop_capturing[index] = false;
+            self.loop_pos[index] = 0;
+            self.emit(EngineEvent::RecordingCancelled(index));
+            return;
+        }
+        // The beats follow the recorded loop exactly, the tempo is only rounded for display
+        self.clock = BeatClock::from_beat_length(samples, beats);
+        self.mbpm = self.clock.mbpm(self.sample_rate);
+        self.loop_clock[index] = self.clock;
+        self.loop_content[index] = self.loop_pos[index];
+        self.pending_mbpm = None;
+        self.emit(EngineEvent::TempoChanged(self.mbpm));
+
+        self.loop_capturing[index] = false;
+        self.loop_filled[index] = true;
+        self.loop_armed[index] = true;
+        // The loop restarts on the first beat of the rolling phase
+        self.loop_recording_start_beat[index] = 1;
+        self.emit(EngineEvent::LoopFilled(index));
+
+        // Start rolling with the next sample, just like after a count-in
+        self.clock.restart();
+        self.current_beat = 0;
+        self.countin_left = 0;
+        self.countin_started = true;
+    }
+
+    /// Start the pad requested by the gui thread
+    fn start_pad(&mut self, idx: usize) {
+        if self.active_pad_count >= MAX_ACTIVE_PADS {
+            return;
+        }
+        if let Some(pad) = self.pads.get(idx) {
+            let new_pad_index = self.active_pads.iter().position(|p| p.is_none()).unwrap();
+            self.active_pads[new_pad_index] = Some(pad.clone());
+            self.active_pads[new_pad_index].as_mut().unwrap().start();
+            self.active_pad_count += 1;
+        }
+    }
+
+    /// Advance the beat counter, update the metronome and the loop states at the beat boundary
+    fn next_beat(&mut self) {
+        // Increase our beat counter
+        self.current_beat += 1;
+
+        // Which beat of the bar this is, counting from 0, decides the accent
+        let beat_in_bar = if self.countin_started {
+            if self.countin_left == 0 {
+                self.countin_started = false;
+                self.emit(EngineEvent::StartedRolling);
+                self.rolling = true;
+                self.current_beat = 1;
+                // The pre-rolls are done, the loops start their first pass right away
+                for index in 0..LOOP_COUNT {
+                    if self.loop_pre_roll[index].is_some() {
+                        self.loop_capturing[index] = false;
+                        if !self.loop_armed[index] {
+                            // The loop was stopped during the count-in
+                            self.loop_pre_roll[index] = None;
+                        }
+                    }
+                }
+                Some(0)
+            } else {
+                self.countin_left -= 1;
+                if self.pre_roll {
+                    self.start_pre_rolls();
+                }
+                // Count backwards so the count-in ends with a full bar
+                let beats_per_bar = self.time_signature.beats_per_bar;
+                Some(beats_per_bar - 1 - self.countin_left % beats_per_bar)
+            }
+        } else if self.rolling {
+            Some(self.time_signature.bar_and_beat(self.current_beat).1 - 1)
+        } else {
+            None
+        };
+
+        self.emit(EngineEvent::BeatTick(self.current_beat));
+
+        if self.rolling {
+            self.update_loops();
+        }
+
+        // The loops are updated first, so the click falls silent right after the first one
+        self.click_volume = self.click_volume() as f32 / 100.0;
+        self.click_tick = 0;
+        let tick = match beat_in_bar {
+            None => Tick::Idle,
+            Some(beat) if self.metronome.is_accented(beat) => Tick::Accent,
+            Some(_) => Tick::Beat,
+        };
+        self.click.trigger(tick, self.click_volume);
+    }
+
+    /// Start recording the rest of the count-in into the end of the loops which record from the
+    /// first beat on, once the count-in is down to their last bar or their length
+    fn start_pre_rolls(&mut self) {
+        // Including the current beat
+        let beats_left = self.countin_left + 1;
+        for index in 0..LOOP_COUNT {
+            let length = self.loop_length[index];
+            if !self.loop_armed[index]
+                || self.loop_filled[index]
+                || self.loop_pre_roll[index].is_some()
+                || !self.has_room(index)
+                || beats_left > length.min(self.time_signature.beats_per_bar)
+            {
+                continue;
+            }
+            // The pre-roll ends right where the first pass does
+            let rolling_beat = self.clock.beat() + beats_left as u64;
+            let rolling_start = self.clock.beat_start(rolling_beat);
+            let pass_length = self.clock.beat_start(rolling_beat + length as u64) - rolling_start;
+            let start = (pass_length - (rolling_start - self.clock.sample())) as usize;
+            self.loop_pre_roll[index] = Some(start);
+            self.loop_pos[index] = start;
+            self.loop_overdubbing[index] = false;
+            self.loop_capturing[index] = true;
+        }
+    }
+
+    /// The volume of the metronome in percent for the current phase
+    fn click_volume(&self) -> u32 {
+        let muted = match self.metronome.mute {
+            ClickMute::Never => false,
+            ClickMute::AfterCountIn => self.rolling,
+            ClickMute::AfterFirstLoop => self.rolling && self.loop_filled.iter().any(|&f| f),
+        };
+        let phase = if self.countin_started {
+            ClickPhase::CountIn
+        } else if self.rolling {
+            ClickPhase::Rolling
+        } else {
+            ClickPhase::Idle
+        };
+        if muted {
+            0
+        } else {
+            self.metronome.volume(phase)
+        }
+    }
+
+    /// Set up loop recording and playback states
+    fn update_loops(&mut self) {
+        let current_beat = self.current_beat;
+        for index in 0..LOOP_COUNT {
+            let length = self.loop_length[index];
+            if !self.at_loop_boundary(index) {
+                continue;
+            }
+            let was_looping = self.loop_looping[index];
+
+            if self.loop_clearing[index] {
+                self.loop_clearing[index] = false;
+                self.loop_filled[index] = false;
+                self.loop_capturing[index] = false;
+                self.loop_overdubbing[index] = false;
+                self.loop_looping[index] = false;
+                self.emit(EngineEvent::LoopCleared(index));
+            }
+
+            if self.loop_capturing[index]
+                && (current_beat - self.loop_recording_start_beat[index]) >= length
+            {
+                // recording ended, start looping
+                self.loop_filled[index] = true;
+                self.loop_capturing[index] = false;
+                self.loop_overdubbing[index] = false;
+                self.loop_looping[index] = true;
+                self.loop_pre_roll[index] = None;
+                self.loop_content[index] = self.loop_pos[index];
+                // The passes played from now on may last longer or shorter than the recording
+                self.loop_recording_start_beat[index] = current_beat;
+                self.emit(EngineEvent::LoopFilled(index));
+            }
+
+            if self.loop_recording_armed[index]
+                && !self.loop_capturing[index]
+                && self.has_room(index)
+            {
+                // Record a single pass, then keep playing it
+                self.loop_recording_armed[index] = false;
+                self.loop_armed[index] = true;
+                self.start_capture(index);
+            }
+
+            if self.loop_armed[index] {
+                if self.loop_capturing[index] {
+                    // Playback during the pass was decided when it started
+                } else if self.loop_filled[index] {
+                    self.loop_looping[index] = true;
+                } else if self.has_room(index) {
+                    self.start_capture(index);
+                }
+            } else if self.loop_filled[index] {
+                self.loop_looping[index] = false;
+            }
+
+            if self.loop_looping[index] {
+                self.loop_pos[index] = 0;
+                self.loop_read[index] = 0.0;
+                if !was_looping {
+                    self.emit(EngineEvent::LoopStartedPlaying(index));
+                }
+            } else if was_looping {
+                self.emit(EngineEvent::LoopStopped(index));
+            }
+        }
+    }
+
+    /// Whether a loop may start, stop or restart at the current beat.
+    ///
+    /// A loop restarts every [`span`](Self::loop_span) beats from where its recording started,
+    /// independent of the other loops. Empty loops start recording on the next downbeat.
+    fn at_loop_boundary(&self, index: usize) -> bool {
+        let length = self.loop_span(index);
+        if length == 0 {
+            false
+        } else if self.loop_filled[index] || self.loop_capturing[index] {
+            self.current_beat
+                .checked_sub(self.loop_recording_start_beat[index])
+                .is_some_and(|beats| beats.is_multiple_of(length))
+        } else {
+            self.time_signature.is_downbeat(self.current_beat)
+        }
+    }
+
+    /// How many beats a pass of a loop lasts, recording passes are never played at another speed
+    fn loop_span(&self, index: usize) -> u32 {
+        let length = self.loop_length[index];
+        if self.loop_capturing[index] {
+            length
+        } else {
+            self.loop_speed[index].span(length)
+        }
+    }
+
+    /// Whether the buffer of a loop can hold a whole pass at the current tempo
+    fn has_room(&self, index: usize) -> bool {
+        let frames = self.clock.samples_in(self.loop_length[index] as u64);
+        self.loop_frames(index) as u64 >= frames
+    }
+
+    /// Whether a loop was recorded at another tempo, so it's played time-stretched
+    fn is_stretched(&self, index: usize) -> bool {
+        self.loop_filled[index] && !self.loop_clock[index].same_tempo(&self.clock)
+    }
+
+    /// How many frames the buffer of a loop holds
+    fn loop_frames(&self, index: usize) -> usize {
+        self.loop_buffers[index].len() / self.loop_channels[index]
+    }
+
+    /// Start capturing the input into a loop for one loop length, starting from the current beat
+    fn start_capture(&mut self, index: usize) {
+        // A pass over a filled loop is either layered on top of the
+        // old take (which keeps playing) or replaces it. A loop
+        // stretched to another tempo or played at another speed
+        // can't be layered.
+        self.loop_overdubbing[index] = self.loop_filled[index]
+            && self.loop_layering[index]
+            && !self.is_stretched(index)
+            && self.loop_speed[index].is_normal();
+        if !self.loop_overdubbing[index] {
+            self.loop_clock[index] = self.clock;
+        }
+        self.loop_looping[index] = self.loop_overdubbing[index];
+        self.loop_capturing[index] = true;
+        self.loop_recording_start_beat[index] = self.current_beat;
+        self.loop_pos[index] = 0;
+        self.emit(EngineEvent::LoopStartedRecording(index));
+    }
+
+    /// Correct the pitch of the input chosen for auto-tune, if any
+    fn tune_inputs(&mut self, mut inputs: InputFrame) -> InputFrame {
+        if let Some(sample) = self
+            .auto_tune_config
+            .input
+            .and_then(|input| inputs.get_mut(input))
+        {
+            *sample = self.auto_tune.apply(*sample);
+        }
+        inputs
+    }
+
+    /// Mix the next sample of every active pad, retiring the ones that ended
+    fn next_pad_mix(&mut self) -> f32 {
+        let mut pad_mix = 0.0;
+        for pad in self.active_pads.iter_mut() {
+            if pad.is_none() {
+                continue;
+            }
+            let some_pad = pad.as_mut().unwrap();
+            if some_pad.ended() {
+                *pad = None;
+                self.active_pad_count -= 1;
+                continue;
+            } else {
+                let sample = some_pad.next_sample();
+                pad_mix += sample;
+                if some_pad.ended() {
+                    *pad = None;
+                    self.active_pad_count -= 1;
+                    continue;
+                }
+            }
+        }
+        pad_mix
+    }
+
+    /// Play back and capture a single frame for every loop, returning the playback mix
+    ///
+    /// Loops are stored interleaved, with as many channels as the input provides. Each loop
+    /// captures the channels of `inputs` its own [`LoopSource`] selects.
+    fn process_loops(&mut self, inputs: &InputFrame, pad_mix: f32) -> Stereo {
+        let mut out_frame = [0.0; 2];
+        for index in 0..LOOP_COUNT {
+            self.loop_output[index] = [0.0; 2];
+            for (gain, target) in self.loop_gain[index]
+                .iter_mut()
+                .zip(self.loop_gain_target[index])
+            {
+                *gain += (target - *gain) * self.gain_smoothing;
+            }
+            let loop_channels = self.loop_channels[index];
+            let fade_target: f32 = if self.loop_reversed[index] { 1.0 } else { 0.0 };
+            let fade = &mut self.loop_reverse_fade[index];
+            *fade = fade_target.clamp(
+                *fade - self.reverse_fade_step,
+                *fade + self.reverse_fade_step,
+            );
+            let fade = *fade;
+            // A layered pass plays the old take at the speed it's recorded at
+            let varispeed = self.loop_looping[index]
+                && !self.loop_overdubbing[index]
+                && !self.loop_speed[index].is_normal();
+            let stretched = self.loop_looping[index] && self.is_stretched(index) && !varispeed;
+            if !stretched {
+                self.stretchers[index].reset(self.loop_pos[index] as f64);
+            }
+            if !varispeed {
+                self.loop_read[index] = self.loop_pos[index] as f64;
+            }
+            if self.loop_pos[index] >= self.loop_frames(index) && !stretched && !varispeed {
+                // The loop doesn't fit into its buffer, leave the rest silent
+                continue;
+            }
+            let start = self.loop_pos[index] * loop_channels;
+
+            if self.loop_looping[index] {
+                let dry_frame = if varispeed {
+                    // Tape-style, the tempo changes the pitch as well
+                    let speed = self.loop_speed[index].ratio()
+                        * self.loop_clock[index].beat_length_ratio(&self.clock);
+                    let content = self.loop_content[index];
+                    let position = self.loop_read[index];
+                    let read = |position| {
+                        read_frame(&self.loop_buffers[index], loop_channels, content, position)
+                    };
+                    // Backwards the pass starts at the last frame
+                    let reversed_position =
+                        (content as f64 - 1.0 - position).rem_euclid(content.max(1) as f64);
+                    let frame = crossfade(fade, || read(position), || read(reversed_position));
+                    self.loop_read[index] = (position + speed) % content.max(1) as f64;
+                    frame
+                } else if stretched {
+                    // The grains crossfade between the directions on their own
+                    let reversed = self.loop_reversed[index];
+                    let stretcher = &mut self.stretchers[index];
+                    if self.loop_pos[index] == 0 {
+                        stretcher.seek(0.0);
+                    }
+                    let speed = self.loop_clock[index].beat_length_ratio(&self.clock);
+                    stretcher.next_frame(
+                        &self.loop_buffers[index],
+                        loop_channels,
+                        self.loop_content[index],
+                        if reversed { -speed } else { speed },
+                    )
+                } else {
+                    let content = self.loop_content[index].min(self.loop_frames(index));
+                    let reversed_start = match content {
+                        0 => start,
+                        _ => (content - 1 - self.loop_pos[index] % content) * loop_channels,
+                    };
+                    let buffer = &self.loop_buffers[index];
+                    // Mono loops play on both sides
+                    let read = |start: usize| {
+                        std::array::from_fn(|channel| buffer[start + channel % loop_channels])
+                    };
+                    crossfade(fade, || read(start), || read(reversed_start))
+                };
+                for (channel, out_sample) in self.loop_output[index].iter_mut().enumerate() {
+                    let sample = self.playback_pitch[index][channel].apply(dry_frame[channel]);
+                    *out_sample = self.playback_delay[index][channel].apply(sample)
+                        * self.loop_gain[index][channel];
+                }
+                for (out_sample, sample) in out_frame.iter_mut().zip(self.loop_output[index]) {
+                    *out_sample += sample;
+                }
+                self.loop_meters[index].add(self.loop_output[index], self.meter_smoothing);
+            }
+
+            if self.loop_capturing[index] {
+                // The first pass leaves the pre-roll at the end of the loop in place
+                let mixing = self.loop_overdubbing[index]
+                    || (self.rolling
+                        && self.loop_pre_roll[index]
+                            .is_some_and(|start| self.loop_pos[index] >= start));
+                let mut captured = self.capture_frame(index, inputs, pad_mix);
+                self.loop_meters[index].add(captured, self.meter_smoothing);
+                if loop_channels == 1 {
+                    captured[0] = (captured[0] + captured[1]) / 2.0;
+                }
+                // A layered pass only covers the take under it, which plays stretched past the
+                // end of the buffer once the tempo drops
+                let frames = if self.loop_overdubbing[index] {
+                    self.loop_content[index].min(self.loop_frames(index))
+                } else {
+                    self.loop_frames(index)
+                };
+                if self.loop_pos[index] < frames {
+                    for (channel, &captured_sample) in
+                        captured.iter().enumerate().take(loop_channels)
+                    {
+                        let sample = &mut self.loop_buffers[index][start + channel];
+                        if mixing {
+                            *sample += captured_sample;
+                        } else {
+                            *sample = captured_sample;
+                        }
+                    }
+                }
+            }
+
+            if self.loop_looping[index] || self.loop_capturing[index] {
+                self.loop_pos[index] += 1;
+            }
+        }
+        out_frame
+    }
+
+    /// Recompute the gains the loops are heading to after a mixer change
+    fn update_loop_gains(&mut self) {
+        let solo = self.loop_mix.iter().any(|mix| mix.soloed);
+        for (target, mix) in self.loop_gain_target.iter_mut().zip(&self.loop_mix) {
+            *target = mix.side_gains(solo);
+        }
+    }
+
+    /// Mix the sources the loop at `index` records into a single frame
+    fn capture_frame(&mut self, index: usize, inputs: &InputFrame, pad_mix: f32) -> Stereo {
+        let source = self.loop_source[index];
+        let mut captured = [0.0; 2];
+        if source.inputs != 0 {
+            let in_frame = self.channels.mix_inputs(inputs, source.inputs);
+            for (captured_sample, in_sample) in captured.iter_mut().zip(in_frame) {
+                *captured_sample += self.distortion.apply(in_sample);
+            }
+        }
+        for (channel, captured_sample) in captured.iter_mut().enumerate() {
+            if source.pads {
+                *captured_sample += pad_mix;
+            }
+            if source.master {
+                *captured_sample += self.master_frame[channel];
+            }
+        }
+        captured
+    }
+}
+
+/// The frame at the fractional `position` of a loop in `buffer`, which holds `frames` frames of
+/// `channels` channels, interpolated linearly and wrapping around at the end
+fn read_frame(buffer: &[f32], channels: usize, frames: usize, position: f64) -> Stereo {
+    let frames = frames.min(buffer.len() / channels.max(1));
+    if frames == 0 {
+        return [0.0; 2];
+    }
+    let first = position as usize % frames;
+    let second = (first + 1) % frames;
+    let fraction = position.fract() as f32;
+    // Mono loops play on both sides
+    std::array::from_fn(|channel| {
+        let channel = channel % channels;
+        let a = buffer[first * channels + channel];
+        let b = buffer[second * channels + channel];
+        a + (b - a) * fraction
+    })
+}
+
+/// Mix the frames of a loop played forwards and backwards, `fade` of the way to backwards.
+/// Only the direction heard is read outside of a crossfade.
+fn crossfade(
+    fade: f32,
+    forwards: impl FnOnce() -> Stereo,
+    backwards: impl FnOnce() -> Stereo,
+) -> Stereo {
+    if fade <= 0.0 {
+        return forwards();
+    }
+    if fade >= 1.0 {
+        return backwards();
+    }
+    let (forwards, backwards) = (forwards(), backwards());
+    std::array::from_fn(|channel| forwards[channel] * (1.0 - fade) + backwards[channel] * fade)
+}
+
+/// The limiter for a side of an output bus
+fn limiter(sample_rate: usize) -> Limiter {
+    Limiter::new(
+        (sample_rate * LIMITER_LOOKAHEAD_MS / 1000).max(1),
+        LIMITER_CEILING,
+        sample_rate * LIMITER_RELEASE_MS / 1000,
+    )
+}
+
+/// How many samples a beat lasts at the fastest tempo of 3000 BPM
+fn samples_per_beat_at_max_tempo(sample_rate: usize) -> u64 {
+    samples_per_beat(sample_rate, 3_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioState, ClickSound, Subdivision, engine_setup};
+    use crate::filter::{Scale, Transpose};
+    use crate::testing::{SAMPLE_RATE, crossings, provide_buffers, rolling_engine, tone};
+
+    /// 120 BPM at 4800 Hz
+    const SAMPLES_PER_BEAT: usize = 2400;
+    const BLOCK_SIZE: usize = 256;
+
+    fn run(engine: &mut AudioEngine, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![0.0; input.len()];
+        for (input, output) in input.chunks(BLOCK_SIZE).zip(output.chunks_mut(BLOCK_SIZE)) {
+            engine.process(&[input], &mut [output]);
+        }
+        output
+    }
+
+    fn events(state: &mut AudioState) -> Vec<EngineEvent> {
+        std::iter::from_fn(|| state.try_recv())
+            .filter(|event| !matches!(event, EngineEvent::BeatTick(_)))
+            .collect()
+    }
+
+    #[test]
+    fn test_disabled_engine_is_silent() {
+        let (mut engine, _state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        let output = run(&mut engine, &[0.5; SAMPLES_PER_BEAT]);
+        assert!(output.iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_countin_then_rolling() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::StartCountIn(4));
+
+        run(&mut engine, &[0.0; 4 * SAMPLES_PER_BEAT]);
+        let ticks: Vec<_> = std::iter::from_fn(|| state.try_recv()).collect();
+        assert_eq!(
+            ticks,
+            (1..=4).map(EngineEvent::BeatTick).collect::<Vec<_>>()
+        );
+
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(
+            std::iter::from_fn(|| state.try_recv()).collect::<Vec<_>>(),
+            vec![EngineEvent::StartedRolling, EngineEvent::BeatTick(1)]
+        );
+        assert_eq!(state.current_millibeat.load(Ordering::Relaxed) / 1000, 1);
+    }
+
+    #[test]
+    fn test_loop_records_then_plays() {
+        let (mut engine, mut state) = rolling_engine(ChannelConfig::default(), 1);
+
+        // Without a count-in, recording starts right away
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(
+            events(&mut state),
+            vec![
+                EngineEvent::StartedRolling,
+                EngineEvent::LoopStartedRecording(0)
+            ]
+        );
+
+        run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+        assert_eq!(
+            events(&mut state),
+            vec![
+                EngineEvent::LoopFilled(0),
+                EngineEvent::LoopStartedPlaying(0)
+            ]
+        );
+
+        // Stopping takes effect at the next loop boundary
+        state.send(EngineCommand::StopLoop(0));
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(events(&mut state), vec![]);
+        run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+        assert_eq!(events(&mut state), vec![EngineEvent::LoopStopped(0)]);
+    }
+
+    #[test]
+    fn test_empty_loop_waits_for_downbeat() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        provide_buffers(&mut state);
+        state.send(EngineCommand::SetTempo(120000));
+        state.set_time_signature("3/4".parse().unwrap());
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::ConfigureLoop {
+            index: 0,
+            length: 2,
+            layering: false,
+        });
+        state.send(EngineCommand::StartCountIn(0));
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(events(&mut state), vec![EngineEvent::StartedRolling]);
+
+        // Armed on the first beat of the bar, the recording starts with the next bar
+        state.send(EngineCommand::ArmLoop(0));
+        run(&mut engine, &[0.0; 2 * SAMPLES_PER_BEAT]);
+        assert_eq!(events(&mut state), vec![]);
+        run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+        assert_eq!(
+            events(&mut state),
+            vec![EngineEvent::LoopStartedRecording(0)]
+        );
+        assert_eq!(engine.current_beat, 4);
+
+        // From then on the loop restarts every two beats
+        run(&mut engine, &[0.0; 2 * SAMPLES_PER_BEAT]);
+        assert_eq!(
+            events(&mut state),
+            vec![
+                EngineEvent::LoopFilled(0),
+                EngineEvent::LoopStartedPlaying(0)
+            ]
+        );
+        run(&mut engine, &[0.0; 2 * SAMPLES_PER_BEAT]);
+        assert_eq!(engine.current_beat, 8);
+        assert_eq!(engine.loop_pos[0], BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_polymetric_loops() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        provide_buffers(&mut state);
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        for (index, length) in [(0, 3), (1, 4)] {
+            state.send(EngineCommand::ConfigureLoop {
+                index,
+                length,
+                layering: false,
+            });
+            state.send(EngineCommand::ArmLoop(index));
+        }
+        state.send(EngineCommand::StartCountIn(0));
+
+        // Both loops start together, then drift apart
+        run(&mut engine, &[0.0; BLOCK_SIZE + 3 * SAMPLES_PER_BEAT]);
+        assert_eq!(engine.current_beat, 4);
+        assert!(engine.loop_looping[0]);
+        assert!(engine.loop_capturing[1]);
+        run(&mut engine, &[0.0; 2 * SAMPLES_PER_BEAT]);
+        assert_eq!(engine.current_beat, 6);
+        assert!(engine.loop_looping[1]);
+        assert_eq!(engine.loop_pos[0], SAMPLES_PER_BEAT * 2 + BLOCK_SIZE);
+        assert_eq!(engine.loop_pos[1], SAMPLES_PER_BEAT + BLOCK_SIZE);
+
+        // The three beat loop restarts on beat 7, the four beat loop on beat 9
+        run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+        assert_eq!(engine.loop_pos[0], BLOCK_SIZE);
+        assert_eq!(engine.loop_pos[1], SAMPLES_PER_BEAT * 2 + BLOCK_SIZE);
+        run(&mut engine, &[0.0; 2 * SAMPLES_PER_BEAT]);
+        assert_eq!(engine.loop_pos[0], SAMPLES_PER_BEAT * 2 + BLOCK_SIZE);
+        assert_eq!(engine.loop_pos[1], BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_free_recording_sets_tempo() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        provide_buffers(&mut state);
+        state.send(EngineCommand::Enable);
+        for (index, length) in [(0, 4), (1, 2)] {
+            state.send(EngineCommand::ConfigureLoop {
+                index,
+                length,
+                layering: false,
+            });
+        }
+        state.send(EngineCommand::ArmLoop(1));
+        state.send(EngineCommand::StartFreeRecording(0));
+
+        // Four beats at 90 BPM, the metronome stays quiet meanwhile
+        let recorded = 4 * SAMPLE_RATE * 60 / 90;
+        let output = run(&mut engine, &vec![0.0; recorded]);
+        assert!(output.iter().all(|&x| x == 0.0));
+        assert_eq!(
+            events(&mut state),
+            vec![EngineEvent::LoopStartedRecording(0)]
+        );
+
+        state.send(EngineCommand::FinishFreeRecording);
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(
+            events(&mut state),
+            vec![
+                EngineEvent::TempoChanged(90000),
+                EngineEvent::LoopFilled(0),
+                EngineEvent::StartedRolling,
+                EngineEvent::LoopStartedPlaying(0),
+                EngineEvent::LoopStartedRecording(1),
+            ]
+        );
+        assert_eq!(engine.clock.beat_start(4) as usize, recorded);
+
+        // Later loops follow the new tempo
+        run(&mut engine, &vec![0.0; 2 * recorded / 4]);
+        assert_eq!(
+            events(&mut state),
+            vec![
+                EngineEvent::LoopFilled(1),
+                EngineEvent::LoopStartedPlaying(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_full_free_recording_rolls_right_away() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        state.buffers.provide(0, 1, SAMPLES_PER_BEAT).unwrap();
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::ConfigureLoop {
+            index: 0,
+            length: 1,
+            layering: false,
+        });
+        state.send(EngineCommand::StartFreeRecording(0));
+        run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+        assert_eq!(
+            events(&mut state),
+            vec![EngineEvent::LoopStartedRecording(0)]
+        );
+
+        // The full buffer ends the recording, the downbeat is the very next frame
+        run(&mut engine, &[0.0; 1]);
+        assert_eq!(
+            events(&mut state),
+            vec![
+                EngineEvent::TempoChanged(120000),
+                EngineEvent::LoopFilled(0),
+                EngineEvent::StartedRolling,
+                EngineEvent::LoopStartedPlaying(0),
+            ]
+        );
+        assert_eq!(state.current_millibeat.load(Ordering::Relaxed) / 1000, 1);
+        assert_eq!(engine.loop_pos[0], 1);
+    }
+
+    #[test]
+    fn test_too_short_free_recording() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        provide_buffers(&mut state);
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::StartFreeRecording(0));
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        events(&mut state);
+
+        // A single block can't be four beats of any playable tempo
+        state.send(EngineCommand::FinishFreeRecording);
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(events(&mut state), vec![EngineEvent::RecordingCancelled(0)]);
+        assert!(!engine.rolling);
+        assert!(!engine.loop_capturing[0]);
+        assert_eq!(engine.free_recording, None);
+
+        // The recording can be started over
+        state.send(EngineCommand::StartFreeRecording(0));
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(
+            events(&mut state),
+            vec![EngineEvent::LoopStartedRecording(0)]
+        );
+    }
+
+    #[test]
+    fn test_loops_need_buffers() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::ConfigureLoop {
+            index: 0,
+            length: 2,
+            layering: false,
+        });
+        state.send(EngineCommand::ArmLoop(0));
+        state.send(EngineCommand::StartCountIn(0));
+
+        // A one beat buffer is too small for a two beat loop
+        state.buffers.provide(0, 1, SAMPLES_PER_BEAT).unwrap();
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(events(&mut state), vec![EngineEvent::StartedRolling]);
+        assert_eq!(engine.loop_buffers[0].len(), SAMPLES_PER_BEAT);
+
+        // The loop starts once it has enough memory
+        let samples = state.buffers.samples_for(2, 120000, 1);
+        state.buffers.provide(0, 1, samples).unwrap();
+        run(&mut engine, &[0.0; 4 * SAMPLES_PER_BEAT]);
+        assert_eq!(
+            events(&mut state),
+            vec![EngineEvent::LoopStartedRecording(0)]
+        );
+
+        // Replacing the buffer throws the recording away
+        state.buffers.release(0).unwrap();
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(events(&mut state), vec![EngineEvent::LoopCleared(0)]);
+        assert!(engine.loop_buffers[0].is_empty());
+        assert_eq!(
+            state.buffers.remaining_samples(),
+            state.buffers.total_samples()
+        );
+        assert!(
+            state
+                .buffers
+                .provide(0, 1, state.buffers.total_samples() + 1)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_stereo_loop() {
+        let channels = ChannelConfig {
+            inputs: 2,
+            outputs: 2,
+            stems: false,
+        };
+        let (mut engine, _state) = rolling_engine(channels, 1);
+
+        // Record an impulse on each side, at different times
+        let mut left = vec![0.0; SAMPLES_PER_BEAT];
+        let mut right = vec![0.0; SAMPLES_PER_BEAT];
+        left[100] = 0.5;
+        right[200] = 0.5;
+        let mut outputs = [vec![0.0; SAMPLES_PER_BEAT], vec![0.0; SAMPLES_PER_BEAT]];
+        let [out_left, out_right] = &mut outputs;
+        engine.process(&[&left, &right], &mut [out_left, out_right]);
+        // Stereo frames take twice the samples
+        assert_eq!(engine.loop_frames(0), 10 * SAMPLE_RATE / 2);
+        assert_eq!(engine.loop_buffers[0][2 * 100], 0.75);
+        assert_eq!(engine.loop_buffers[0][2 * 100 + 1], 0.0);
+        assert_eq!(engine.loop_buffers[0][2 * 200 + 1], 0.75);
+
+        // And play them back on their own side
+        let silence = vec![0.0; SAMPLES_PER_BEAT];
+        let [out_left, out_right] = &mut outputs;
+        engine.process(&[&silence, &silence], &mut [out_left, out_right]);
+        let latency = engine.output_latency();
+        assert!(outputs[0][100 + latency] > outputs[1][100 + latency]);
+        assert!(outputs[1][200 + latency] > outputs[0][200 + latency]);
+        assert_eq!(outputs[0][300 + latency], outputs[1][300 + latency]);
+    }
+
+    #[test]
+    fn test_loops_record_their_own_sources() {
+        let channels = ChannelConfig {
+            inputs: 4,
+            outputs: 2,
+            stems: false,
+        };
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, channels);
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        let sources = [
+            (0b0001, false, false),
+            (0b0010, false, false),
+            (0, true, false),
+            (0, false, true),
+        ];
+        for (index, (inputs, pads, master)) in sources.into_iter().enumerate() {
+            let source = LoopSource {
+                inputs,
+                pads,
+                master,
+            };
+            let loop_channels = source.channels(channels);
+            state
+                .buffers
+                .provide(index, loop_channels, loop_channels * 10 * SAMPLE_RATE)
+                .unwrap();
+            state.send(EngineCommand::ConfigureLoop {
+                index,
+                length: 1,
+                layering: false,
+            });
+            state.send(EngineCommand::SetLoopSource { index, source });
+            state.send(EngineCommand::ArmLoop(index));
+        }
+        state.send(EngineCommand::StartCountIn(0));
+
+        // Guitar on the first input, vocals on the second one
+        let mut guitar = vec![0.0; SAMPLES_PER_BEAT];
+        let mut vocals = vec![0.0; SAMPLES_PER_BEAT];
+        guitar[100] = 0.5;
+        vocals[200] = 0.5;
+        let silence = vec![0.0; SAMPLES_PER_BEAT];
+        let mut outputs = [vec![0.0; SAMPLES_PER_BEAT], vec![0.0; SAMPLES_PER_BEAT]];
+        let [out_left, out_right] = &mut outputs;
+        engine.process(
+            &[&guitar, &vocals, &silence, &silence],
+            &mut [out_left, out_right],
+        );
+
+        // A single input is recorded in mono, taking half the memory
+        assert_eq!(engine.loop_frames(0), 10 * SAMPLE_RATE);
+        assert_eq!(engine.loop_buffers[0][100], 0.75);
+        assert_eq!(engine.loop_buffers[0][200], 0.0);
+        assert_eq!(engine.loop_buffers[1][100], 0.0);
+        assert_eq!(engine.loop_buffers[1][200], 0.75);
+        // No pad was played
+        assert!(engine.loop_buffers[2].iter().all(|&sample| sample == 0.0));
+        // The master mix is resampled in stereo a frame late, without the metronome
+        assert_eq!(engine.loop_frames(3), 10 * SAMPLE_RATE);
+        assert_eq!(engine.loop_buffers[3][2 * 50], 0.0);
+        assert!(engine.loop_buffers[3][2 * 101] != 0.0);
+    }
+
+    #[test]
+    fn test_stem_outputs() {
+        let channels = ChannelConfig {
+            inputs: 1,
+            outputs: 2,
+            stems: true,
+        };
+        let (mut engine, _state) = rolling_engine(channels, 1);
+
+        let mut outputs = vec![vec![0.0; SAMPLES_PER_BEAT]; channels.output_port_count()];
+        let mut input = vec![0.0; SAMPLES_PER_BEAT];
+        input[100] = 0.5;
+        for input in [input, vec![0.0; SAMPLES_PER_BEAT]] {
+            let mut slices: Vec<&mut [f32]> = outputs.iter_mut().map(|o| &mut o[..]).collect();
+            engine.process(&[&input], &mut slices);
+        }
+
+        // The second beat plays back the first one on the stem of the first loop only
+        let (buses, stems) = outputs.split_at(4);
+        let (main, cue) = buses.split_at(2);
+        assert_eq!(stems[0][100], 0.75);
+        assert_eq!(stems[1][100], 0.75);
+        assert!(stems[2..2 * LOOP_COUNT].iter().flatten().all(|&x| x == 0.0));
+        let (pads, click) = (&stems[2 * LOOP_COUNT], &stems[2 * LOOP_COUNT + 1]);
+        assert!(pads.iter().all(|&x| x == 0.0));
+        assert!(click.iter().any(|&x| x != 0.0));
+        // The main outputs still carry the loops (and the echo of the input), but the metronome
+        // is only heard on the cue bus, both a bit later than the stems
+        let latency = engine.output_latency();
+        assert!(main[0][100 + latency] > stems[0][100]);
+        assert_eq!(main[0][300 + latency], 0.0);
+        assert_eq!(cue[0][300 + latency], click[300]);
+    }
+
+    #[test]
+    fn test_loop_mixer() {
+        let channels = ChannelConfig {
+            inputs: 1,
+            outputs: 2,
+            stems: true,
+        };
+        let (mut engine, mut state) = rolling_engine(channels, 1);
+
+        let mut outputs = vec![vec![0.0; SAMPLES_PER_BEAT]; channels.output_port_count()];
+        let mut process = |engine: &mut AudioEngine, input: f32| {
+            let input = vec![input; SAMPLES_PER_BEAT];
+            let mut slices: Vec<&mut [f32]> = outputs.iter_mut().map(|o| &mut o[..]).collect();
+            engine.process(&[&input], &mut slices);
+            // The stem of the first loop follows the main and cue outputs
+            [outputs[4].clone(), outputs[5].clone()]
+        };
+        process(&mut engine, 0.5);
+
+        // Panning hard left fades out the right side, without a jump
+        let mut mix = LoopMix::default();
+        mix.adjust_pan(-LoopMix::MAX_PAN);
+        state.send(EngineCommand::SetLoopMix { index: 0, mix });
+        let [left, right] = process(&mut engine, 0.0);
+        assert!(left[SAMPLES_PER_BEAT - 1] > 0.7);
+        assert!(right[0] > 0.7);
+        assert!(right[SAMPLES_PER_BEAT - 1] < 0.001);
+
+        // Soloing another loop silences this one
+        state.send(EngineCommand::SetLoopMix {
+            index: 1,
+            mix: LoopMix {
+                soloed: true,
+                ..LoopMix::default()
+            },
+        });
+        let [left, _] = process(&mut engine, 0.0);
+        assert!(left[0] > 0.7);
+        assert!(left[SAMPLES_PER_BEAT - 1] < 0.001);
+
+        // The next session starts with a neutral mixer
+        state.send(EngineCommand::Disable);
+        process(&mut engine, 0.0);
+        assert_eq!(engine.loop_mix, [LoopMix::default(); LOOP_COUNT]);
+        assert_eq!(engine.loop_gain, engine.loop_gain_target);
+        assert!(engine.loop_gain[0][0] > 0.7);
+    }
+
+    #[test]
+    fn test_disable_resets_playback() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        state.send(EngineCommand::Enable);
+        for index in 0..LOOP_COUNT {
+            state.send(EngineCommand::SetLoopSpeed {
+                index,
+                speed: LoopSpeed::HALF,
+            });
+            state.send(EngineCommand::SetLoopReverse {
+                index,
+                reversed: true,
+            });
+        }
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(engine.loop_speed, [LoopSpeed::HALF; LOOP_COUNT]);
+
+        // Loops the next session doesn't configure don't keep the old settings either
+        state.send(EngineCommand::Disable);
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(engine.loop_speed, [LoopSpeed::NORMAL; LOOP_COUNT]);
+        assert_eq!(engine.loop_reversed, [false; LOOP_COUNT]);
+        assert_eq!(engine.loop_reverse_fade, [0.0; LOOP_COUNT]);
+    }
+
+    #[test]
+    fn test_bus_levels() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        state.adjust_level(Level::Main, -50);
+        state.adjust_level(Level::CueMonitor, -100);
+
+        let mut input = vec![0.0; SAMPLES_PER_BEAT];
+        input[300] = 0.5;
+        let (mut main, mut cue) = (vec![0.0; SAMPLES_PER_BEAT], vec![0.0; SAMPLES_PER_BEAT]);
+        engine.process(&[&input], &mut [&mut main, &mut cue]);
+
+        // The input is on the main bus at half the level, the click only on the cue bus
+        assert_eq!(main[300 + engine.output_latency()], 0.375);
+        assert!(main[..200].iter().all(|&x| x == 0.0));
+        assert!(cue[..200].iter().any(|&x| x != 0.0));
+
+        // Without the click, there's nothing left on the cue bus
+        state.adjust_level(Level::CueClick, -100);
+        engine.process(&[&input], &mut [&mut main, &mut cue]);
+        assert!(cue.iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_limiter_reports_clipping() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        state.send(EngineCommand::Enable);
+        state.adjust_level(Level::Main, 100);
+
+        let output = run(&mut engine, &[0.9; SAMPLES_PER_BEAT]);
+        assert!(output.iter().all(|&x| x.abs() <= LIMITER_CEILING));
+        let clipped: u32 = events(&mut state)
+            .into_iter()
+            .map(|event| match event {
+                EngineEvent::Clipped(count) => count,
+                _ => 0,
+            })
+            .sum();
+        assert!(clipped > 0);
+    }
+
+    #[test]
+    fn test_meters() {
+        let (mut engine, mut state) = rolling_engine(ChannelConfig::default(), 1);
+        run(&mut engine, &[0.5; SAMPLES_PER_BEAT]);
+
+        let meters = state.meters.clone();
+        assert_eq!(meters.take_peak(MeterSource::Input), 0.5);
+        // The RMS level is still rising after half a second
+        let rms = meters.rms(MeterSource::Input);
+        assert!(rms > 0.4 && rms < 0.5);
+        // The recorded loop is metered as well, the distortion turns 0.5 into 0.75
+        assert_eq!(meters.take_peak(MeterSource::Loop(0)), 0.75);
+        assert_eq!(meters.take_peak(MeterSource::Loop(1)), 0.0);
+        assert!(meters.take_peak(MeterSource::Master) > 0.5);
+
+        // Nothing is left to meter once the engine is disabled
+        state.send(EngineCommand::Disable);
+        run(&mut engine, &[0.5; BLOCK_SIZE]);
+        assert_eq!(meters.rms(MeterSource::Input), 0.0);
+    }
+
+    #[test]
+    fn test_metronome() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        let mut metronome = MetronomeConfig::default();
+        metronome.subdivision = Subdivision::Eighths;
+        metronome.mute = ClickMute::AfterCountIn;
+        state.set_metronome(metronome);
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::StartCountIn(2));
+
+        let input = vec![0.0; SAMPLES_PER_BEAT];
+        let (mut main, mut cue) = (vec![0.0; SAMPLES_PER_BEAT], vec![0.0; SAMPLES_PER_BEAT]);
+        engine.process(&[&input], &mut [&mut main, &mut cue]);
+
+        // The beat is clicked, then its second half
+        let half = SAMPLES_PER_BEAT / 2 + engine.output_latency();
+        assert!(cue[..100].iter().any(|&x| x != 0.0));
+        assert!(cue[half - 200..half].iter().all(|&x| x == 0.0));
+        assert!(cue[half..half + 100].iter().any(|&x| x != 0.0));
+
+        // The click falls silent once rolling
+        engine.process(&[&input], &mut [&mut main, &mut cue]);
+        engine.process(&[&input], &mut [&mut main, &mut cue]);
+        assert!(engine.rolling);
+        let latency = engine.output_latency();
+        assert!(cue[latency..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_click_sample_from_file() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        let write_wav = |name: &str, channels: u16| {
+            let path =
+                std::env::temp_dir().join(format!("loooper_{}_{}", std::process::id(), name));
+            let spec = hound::WavSpec {
+                channels,
+                sample_rate: SAMPLE_RATE as u32,
+                bits_per_sample: 16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for _ in 0..100 {
+                writer.write_sample(i16::MAX / 2).unwrap();
+            }
+            writer.finalize().unwrap();
+            path
+        };
+
+        // Only mono files are taken
+        let stereo = write_wav("stereo.wav", 2);
+        assert!(state.set_click_file(stereo.clone()).is_err());
+        assert_eq!(state.click_file, None);
+        let mono = write_wav("mono.wav", 1);
+        state.set_click_file(mono.clone()).unwrap();
+        assert_eq!(state.click_file, Some(mono.clone()));
+        let _ = std::fs::remove_file(stereo);
+        let _ = std::fs::remove_file(mono);
+
+        let mut metronome = MetronomeConfig::default();
+        metronome.sound = ClickSound::Sample;
+        state.set_metronome(metronome);
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::StartCountIn(2));
+        let input = vec![0.0; BLOCK_SIZE];
+        let (mut main, mut cue) = (vec![0.0; BLOCK_SIZE], vec![0.0; BLOCK_SIZE]);
+        engine.process(&[&input], &mut [&mut main, &mut cue]);
+
+        // The flat sample is clicked instead of the built-in one
+        let latency = engine.output_latency();
+        let click = &cue[latency..latency + 100];
+        assert!(click[0] > 0.0);
+        assert!(click.iter().all(|&x| x == click[0]));
+        assert!(cue[latency + 100..].iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_pre_roll() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        provide_buffers(&mut state);
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::ConfigureLoop {
+            index: 0,
+            length: 8,
+            layering: false,
+        });
+        state.send(EngineCommand::ArmLoop(0));
+        state.send(EngineCommand::SetPreRoll(true));
+        state.send(EngineCommand::StartCountIn(8));
+
+        // Notes in the first and the last bar of the count-in, on the first beat and on top of
+        // the pickup a pass later
+        let mut input = vec![0.0; 16 * SAMPLES_PER_BEAT + BLOCK_SIZE];
+        input[3 * SAMPLES_PER_BEAT + 100] = 0.5;
+        input[7 * SAMPLES_PER_BEAT + 100] = 0.5;
+        input[8 * SAMPLES_PER_BEAT + 50] = 0.5;
+        input[15 * SAMPLES_PER_BEAT + 100] = 0.5;
+        run(&mut engine, &input);
+        assert!(events(&mut state).contains(&EngineEvent::LoopFilled(0)));
+
+        // Only the last bar of the count-in is kept, at the end of the loop and mixed with the
+        // first pass. The distortion on the input turns 0.5 into 0.75.
+        let buffer = &engine.loop_buffers[0];
+        assert_eq!(buffer[50], 0.75);
+        assert_eq!(buffer[3 * SAMPLES_PER_BEAT + 100], 0.0);
+        assert_eq!(buffer[7 * SAMPLES_PER_BEAT + 100], 1.5);
+    }
+
+    #[test]
+    fn test_tempo_ramp_stretches_loops() {
+        let (mut engine, mut state) = rolling_engine(ChannelConfig::default(), 1);
+
+        // Record a 100 Hz tone, then go half as fast within two beats
+        let tone = tone(100.0, 0.5);
+        run(&mut engine, &tone);
+        state.send(EngineCommand::RampTempo {
+            mbpm: 60000,
+            beats: 2,
+        });
+        run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+        run(&mut engine, &[0.0; 3 * SAMPLES_PER_BEAT / 2]);
+        let tempos: Vec<_> = events(&mut state)
+            .into_iter()
+            .filter(|event| matches!(event, EngineEvent::TempoChanged(_)))
+            .collect();
+        assert_eq!(
+            tempos,
+            vec![
+                EngineEvent::TempoChanged(90000),
+                EngineEvent::TempoChanged(60000)
+            ]
+        );
+        assert!(engine.is_stretched(0));
+
+        // A whole pass at the new tempo keeps the pitch: 100 Hz crosses zero 200 times a second
+        let output = run(&mut engine, &[0.0; 2 * SAMPLES_PER_BEAT]);
+        let crossings = crossings(&output);
+        assert!(
+            crossings.abs_diff(200) <= 10,
+            "{} zero crossings",
+            crossings
+        );
+        // The loop follows the beats: 3200 samples at 90 BPM, then 4800 samples per pass
+        let since_ramp = SAMPLES_PER_BEAT + 3 * SAMPLES_PER_BEAT / 2 + 2 * SAMPLES_PER_BEAT;
+        assert_eq!(engine.loop_pos[0], (since_ramp - 3200) % 4800);
+    }
+
+    #[test]
+    fn test_half_speed_loop() {
+        let (mut engine, mut state) = rolling_engine(ChannelConfig::default(), 2);
+
+        // Record a 100 Hz tone, then play it half as fast
+        let tone = tone(100.0, 1.0);
+        run(&mut engine, &tone);
+        state.send(EngineCommand::SetLoopSpeed {
+            index: 0,
+            speed: LoopSpeed::HALF,
+        });
+        // Past the echo of the monitor, the pitch drops an octave: 50 Hz crosses zero 100 times a
+        // second
+        let output = run(&mut engine, &[0.0; 4 * SAMPLES_PER_BEAT]);
+        let crossings = crossings(&output[SAMPLES_PER_BEAT..]);
+        assert!(crossings.abs_diff(150) <= 8, "{} zero crossings", crossings);
+
+        // A pass spans four beats instead of two
+        run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+        assert_eq!(engine.loop_pos[0], SAMPLES_PER_BEAT);
+        assert_eq!(engine.loop_read[0], (SAMPLES_PER_BEAT / 2) as f64);
+
+        state.send(EngineCommand::SetLoopSpeed {
+            index: 0,
+            speed: LoopSpeed::DOUBLE,
+        });
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(engine.loop_span(0), 1);
+        assert_eq!(
+            events(&mut state),
+            vec![
+                EngineEvent::StartedRolling,
+                EngineEvent::LoopStartedRecording(0),
+                EngineEvent::LoopFilled(0),
+                EngineEvent::LoopStartedPlaying(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reversed_loop() {
+        let (mut engine, mut state) = rolling_engine(ChannelConfig::default(), 2);
+
+        // Record a rising ramp, then play it backwards
+        let content = 2 * SAMPLES_PER_BEAT;
+        let ramp: Vec<f32> = (0..content).map(|n| n as f32 / content as f32).collect();
+        run(&mut engine, &ramp);
+        state.send(EngineCommand::SetLoopReverse {
+            index: 0,
+            reversed: true,
+        });
+
+        // Past the crossfade, the pass starts at the end of the recording. The echo of the loop
+        // isn't heard yet.
+        for played in [content / 8, content / 4] {
+            run(&mut engine, &vec![0.0; content / 8]);
+            assert_eq!(engine.loop_reverse_fade[0], 1.0);
+            assert_eq!(
+                engine.loop_output[0][0],
+                engine.loop_buffers[0][content - played]
+            );
+        }
+        // It keeps the boundaries of the loop
+        run(&mut engine, &vec![0.0; content * 3 / 4 + 1]);
+        assert_eq!(engine.loop_pos[0], 1);
+
+        // Going forwards again crossfades back
+        state.send(EngineCommand::SetLoopReverse {
+            index: 0,
+            reversed: false,
+        });
+        run(&mut engine, &[0.0; BLOCK_SIZE / 8]);
+        assert!(engine.loop_reverse_fade[0] > 0.0 && engine.loop_reverse_fade[0] < 1.0);
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(engine.loop_reverse_fade[0], 0.0);
+    }
+
+    #[test]
+    fn test_transposed_loop() {
+        let (mut engine, mut state) = rolling_engine(ChannelConfig::default(), 2);
+
+        // Record a 100 Hz tone, then play it an octave higher
+        let tone = tone(100.0, 1.0);
+        run(&mut engine, &tone);
+        state.send(EngineCommand::SetLoopTranspose {
+            index: 0,
+            transpose: Transpose::new(12, 0),
+        });
+        // Past the echo of the monitor, 200 Hz crosses zero 400 times a second
+        let output = run(&mut engine, &[0.0; 4 * SAMPLES_PER_BEAT]);
+        let crossings = crossings(&output[SAMPLES_PER_BEAT..]);
+        assert!(
+            crossings.abs_diff(600) <= 30,
+            "{} zero crossings",
+            crossings
+        );
+    }
+
+    #[test]
+    fn test_auto_tune_before_capture() {
+        let (mut engine, mut state) = rolling_engine(ChannelConfig::default(), 2);
+        state.send(EngineCommand::SetAutoTune(AutoTuneConfig {
+            input: Some(0),
+            scale: Scale::Major,
+            retune_ms: 0,
+            ..Default::default()
+        }));
+
+        // Sing 425 Hz, which is nearest to the A at 440 Hz in C major
+        let voice = tone(425.0, 1.0);
+        run(&mut engine, &voice);
+        run(&mut engine, &[0.0; BLOCK_SIZE]);
+        assert_eq!(events(&mut state)[2], EngineEvent::LoopFilled(0));
+
+        // Past the first detection, 440 Hz crosses zero 880 times a second
+        let recorded = &engine.loop_buffers[0][SAMPLE_RATE / 4..2 * SAMPLES_PER_BEAT];
+        let crossings = crossings(recorded);
+        assert!(crossings.abs_diff(660) <= 8, "{} zero crossings", crossings);
+    }
+
+    #[test]
+    fn test_countin_ends_with_full_bar() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        state.send(EngineCommand::SetTempo(120000));
+        state.set_time_signature("3/4".parse().unwrap());
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::StartCountIn(6));
+
+        // Only the first beat of each count-in bar is accented
+        let mut accents = Vec::new();
+        for _ in 0..6 {
+            run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+            accents.push(engine.click.tick() == Tick::Accent);
+        }
+        assert_eq!(accents, vec![true, false, false, true, false, false]);
+    }
+
+    #[test]
+    fn test_tempo_drop_during_layered_pass() {
+        let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+        // Just enough memory for a pass at the recording tempo
+        state.buffers.provide(0, 1, 4 * SAMPLES_PER_BEAT).unwrap();
+        state.send(EngineCommand::SetTempo(120000));
+        state.send(EngineCommand::Enable);
+        state.send(EngineCommand::ConfigureLoop {
+            index: 0,
+            length: 4,
+            layering: true,
+        });
+        state.send(EngineCommand::ArmLoop(0));
+        state.send(EngineCommand::StartCountIn(0));
+        run(&mut engine, &[0.5; 4 * SAMPLES_PER_BEAT + BLOCK_SIZE]);
+        state.send(EngineCommand::RecordLoop(0));
+        run(&mut engine, &[0.0; 4 * SAMPLES_PER_BEAT]);
+        assert!(engine.loop_overdubbing[0]);
+
+        // The rest of the pass lasts three times as long, but only covers the old take
+        state.send(EngineCommand::SetTempo(40000));
+        run(&mut engine, &[0.5; 8 * SAMPLES_PER_BEAT]);
+        assert!(engine.loop_overdubbing[0]);
+        assert!(engine.loop_pos[0] > engine.loop_frames(0));
+        run(&mut engine, &[0.5; 6 * SAMPLES_PER_BEAT]);
+        assert!(events(&mut state).contains(&EngineEvent::LoopFilled(0)));
+        assert!(!engine.loop_overdubbing[0]);
+    }
+
+    #[test]
+    fn test_record_pass_layers_or_overwrites() {
+        // The distortion on the input turns 0.5 into 0.75
+        for (layering, expected) in [(true, 0.0), (false, -0.75)] {
+            let (mut engine, mut state, _) = engine_setup(SAMPLE_RATE, ChannelConfig::default());
+            provide_buffers(&mut state);
+            state.send(EngineCommand::SetTempo(120000));
+            state.send(EngineCommand::Enable);
+            state.send(EngineCommand::ConfigureLoop {
+                index: 0,
+                length: 1,
+                layering,
+            });
+            state.send(EngineCommand::ArmLoop(0));
+            state.send(EngineCommand::StartCountIn(0));
+
+            let mut input = vec![0.0; SAMPLES_PER_BEAT + BLOCK_SIZE];
+            input[100] = 0.5;
+            run(&mut engine, &input);
+            assert_eq!(engine.loop_buffers[0][100], 0.75);
+
+            // The new pass starts at the next loop boundary
+            state.send(EngineCommand::RecordLoop(0));
+            let mut input = vec![0.0; SAMPLES_PER_BEAT];
+            input[SAMPLES_PER_BEAT - BLOCK_SIZE + 100] = -0.5;
+            run(&mut engine, &input);
+            assert_eq!(engine.loop_buffers[0][100], expected);
+            assert_eq!(engine.loop_looping[0], layering);
+
+            // And the loop plays again after a single pass
+            events(&mut state);
+            run(&mut engine, &[0.0; SAMPLES_PER_BEAT]);
+            let events = events(&mut state);
+            assert_eq!(events[0], EngineEvent::LoopFilled(0));
+            assert!(engine.loop_looping[0]);
+            assert_eq!(engine.loop_buffers[0][100], expected);
+        }
+    }
+}
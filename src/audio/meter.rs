@@ -0,0 +1,117 @@
+use super::channels::Stereo;
+use super::engine::LOOP_COUNT;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// How many signals are metered: the input, every loop and the master.
+pub const METER_COUNT: usize = LOOP_COUNT + 2;
+
+/// How long the RMS level averages over.
+const RMS_WINDOW_MS: f32 = 300.0;
+
+/// A metered signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MeterSource {
+    /// The input, before the monitoring effects.
+    Input,
+    /// What a loop plays back or records.
+    Loop(usize),
+    /// The main bus, as it leaves the limiter.
+    Master,
+}
+
+impl std::fmt::Display for MeterSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MeterSource::Input => write!(f, "Input"),
+            MeterSource::Loop(index) => write!(f, "Loop {}", index + 1),
+            MeterSource::Master => write!(f, "Master"),
+        }
+    }
+}
+
+impl MeterSource {
+    /// A distinct index below [`METER_COUNT`] for every source.
+    pub fn index(&self) -> usize {
+        match *self {
+            MeterSource::Input => 0,
+            MeterSource::Loop(index) => 1 + index.min(LOOP_COUNT - 1),
+            MeterSource::Master => LOOP_COUNT + 1,
+        }
+    }
+}
+
+/// Peak and RMS levels published by the [`AudioEngine`](super::AudioEngine) after every block,
+/// without locking.
+///
+/// Levels are linear, 1.0 being full scale. Peaks are kept until they're taken, so none is
+/// missed by a reader polling less often than the engine publishes.
+#[derive(Debug, Default)]
+pub struct Meters {
+    peak: [AtomicU32; METER_COUNT],
+    rms: [AtomicU32; METER_COUNT],
+}
+
+impl Meters {
+    /// Publish the levels of `source`.
+    fn publish(&self, source: MeterSource, peak: f32, rms: f32) {
+        let index = source.index();
+        // The bits of positive floats are ordered just like the floats
+        self.peak[index].fetch_max(peak.to_bits(), Ordering::Relaxed);
+        self.rms[index].store(rms.to_bits(), Ordering::Relaxed);
+    }
+
+    /// The highest peak of `source` since the last call.
+    pub fn take_peak(&self, source: MeterSource) -> f32 {
+        f32::from_bits(self.peak[source.index()].swap(0, Ordering::Relaxed))
+    }
+
+    /// The current RMS level of `source`.
+    pub fn rms(&self, source: MeterSource) -> f32 {
+        f32::from_bits(self.rms[source.index()].load(Ordering::Relaxed))
+    }
+}
+
+/// Measures a signal inside the engine, see [`Meters`].
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct Meter {
+    peak: f32,
+    mean_square: f32,
+}
+
+impl Meter {
+    /// How much of the way to the current square the mean square takes each sample.
+    pub fn smoothing(sample_rate: usize) -> f32 {
+        1.0 - (-1000.0 / (RMS_WINDOW_MS * sample_rate as f32)).exp()
+    }
+
+    /// Measure a frame, louder side first.
+    pub fn add(&mut self, frame: Stereo, smoothing: f32) {
+        let level = frame[0].abs().max(frame[1].abs());
+        self.peak = self.peak.max(level);
+        self.mean_square += (level * level - self.mean_square) * smoothing;
+    }
+
+    /// Publish what was measured since the last call.
+    pub fn publish(&mut self, meters: &Meters, source: MeterSource) {
+        meters.publish(source, self.peak, self.mean_square.sqrt());
+        self.peak = 0.0;
+    }
+}
+
+#[test]
+fn test_meters() {
+    let meters = Meters::default();
+    let mut meter = Meter::default();
+    for _ in 0..10000 {
+        meter.add([0.5, -0.25], Meter::smoothing(4800));
+    }
+    meter.publish(&meters, MeterSource::Loop(2));
+    meter.add([0.1, 0.0], 0.0);
+    meter.publish(&meters, MeterSource::Loop(2));
+
+    // The louder peak is kept until it's taken
+    assert_eq!(meters.take_peak(MeterSource::Loop(2)), 0.5);
+    assert_eq!(meters.take_peak(MeterSource::Loop(2)), 0.0);
+    assert!((meters.rms(MeterSource::Loop(2)) - 0.5).abs() < 0.001);
+    assert_eq!(meters.rms(MeterSource::Input), 0.0);
+}
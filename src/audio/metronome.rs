@@ -0,0 +1,324 @@
+use super::adsr::ADSR;
+use super::oscillator::{Oscillator, Waveform};
+use super::sample::SamplePad;
+use super::time_signature::TimeSignature;
+
+/// What the metronome sounds like.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClickSound {
+    Sine,
+    Square,
+    Triangle,
+    /// A WAV sample, `src/sounds/click.wav` unless another file is picked in the setup screen.
+    /// Accents are played louder instead of higher.
+    Sample,
+}
+
+impl ClickSound {
+    pub const ALL: [ClickSound; 4] = [
+        ClickSound::Sine,
+        ClickSound::Square,
+        ClickSound::Triangle,
+        ClickSound::Sample,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClickSound::Sine => "Sine",
+            ClickSound::Square => "Square",
+            ClickSound::Triangle => "Triangle",
+            ClickSound::Sample => "Sample",
+        }
+    }
+
+    /// The next sound, wrapping around.
+    pub fn next(&self) -> ClickSound {
+        let index = Self::ALL
+            .iter()
+            .position(|sound| sound == self)
+            .unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// The phases the metronome has a volume for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClickPhase {
+    /// Before the count-in, while there's nothing to play along to yet.
+    Idle,
+    CountIn,
+    Rolling,
+}
+
+impl ClickPhase {
+    pub const ALL: [ClickPhase; 3] = [ClickPhase::Idle, ClickPhase::CountIn, ClickPhase::Rolling];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClickPhase::Idle => "Idle",
+            ClickPhase::CountIn => "Count-in",
+            ClickPhase::Rolling => "Rolling",
+        }
+    }
+
+    /// The next phase, wrapping around.
+    pub fn next(&self) -> ClickPhase {
+        let index = Self::ALL
+            .iter()
+            .position(|phase| phase == self)
+            .unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// How many clicks the metronome plays per beat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subdivision {
+    /// Only the beats.
+    None,
+    Eighths,
+    Triplets,
+}
+
+impl Subdivision {
+    pub const ALL: [Subdivision; 3] = [
+        Subdivision::None,
+        Subdivision::Eighths,
+        Subdivision::Triplets,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subdivision::None => "Off",
+            Subdivision::Eighths => "8ths",
+            Subdivision::Triplets => "Triplets",
+        }
+    }
+
+    /// How many clicks a beat is divided into.
+    pub fn ticks_per_beat(&self) -> u32 {
+        match self {
+            Subdivision::None => 1,
+            Subdivision::Eighths => 2,
+            Subdivision::Triplets => 3,
+        }
+    }
+
+    /// The next subdivision, wrapping around.
+    pub fn next(&self) -> Subdivision {
+        let index = Self::ALL.iter().position(|sub| sub == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// When the metronome falls silent while rolling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClickMute {
+    Never,
+    AfterCountIn,
+    /// Once any loop is filled, so the loops keep the time instead.
+    AfterFirstLoop,
+}
+
+impl ClickMute {
+    pub const ALL: [ClickMute; 3] = [
+        ClickMute::Never,
+        ClickMute::AfterCountIn,
+        ClickMute::AfterFirstLoop,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClickMute::Never => "Never",
+            ClickMute::AfterCountIn => "After Count-in",
+            ClickMute::AfterFirstLoop => "After First Loop",
+        }
+    }
+
+    /// The next option, wrapping around.
+    pub fn next(&self) -> ClickMute {
+        let index = Self::ALL.iter().position(|mute| mute == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// The settings of the metronome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetronomeConfig {
+    pub sound: ClickSound,
+    /// The volume of each [`ClickPhase`] in percent of full scale.
+    volume: [u32; ClickPhase::ALL.len()],
+    /// A bit for each beat of the bar, set if the beat is accented.
+    accents: u16,
+    pub subdivision: Subdivision,
+    pub mute: ClickMute,
+}
+
+impl Default for MetronomeConfig {
+    fn default() -> Self {
+        MetronomeConfig {
+            sound: ClickSound::Sine,
+            volume: [20, 40, 5],
+            accents: 1,
+            subdivision: Subdivision::None,
+            mute: ClickMute::Never,
+        }
+    }
+}
+
+impl MetronomeConfig {
+    pub const MAX_VOLUME: u32 = 100;
+
+    pub fn volume(&self, phase: ClickPhase) -> u32 {
+        self.volume[phase as usize]
+    }
+
+    /// Change the volume of `phase` by `delta` percent.
+    pub fn adjust_volume(&mut self, phase: ClickPhase, delta: i32) {
+        let volume = &mut self.volume[phase as usize];
+        *volume = volume.saturating_add_signed(delta).min(Self::MAX_VOLUME);
+    }
+
+    /// Whether `beat` (counting from 0) of the bar is accented.
+    pub fn is_accented(&self, beat: u32) -> bool {
+        beat < TimeSignature::MAX_BEATS_PER_BAR && self.accents & (1 << beat) != 0
+    }
+
+    /// Accent `beat` (counting from 0) of the bar, or take its accent away.
+    pub fn toggle_accent(&mut self, beat: u32) {
+        if beat < TimeSignature::MAX_BEATS_PER_BAR {
+            self.accents ^= 1 << beat;
+        }
+    }
+
+    /// The accents of a bar of `beats` beats, e.g. `>..>..`.
+    pub fn describe_accents(&self, beats: u32) -> String {
+        (0..beats)
+            .map(|beat| if self.is_accented(beat) { '>' } else { '.' })
+            .collect()
+    }
+}
+
+/// The kinds of clicks the metronome plays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Tick {
+    /// A beat before the count-in.
+    Idle,
+    Accent,
+    Beat,
+    /// A click between the beats.
+    Subdivision,
+}
+
+impl Tick {
+    fn freq(&self) -> f32 {
+        match self {
+            Tick::Idle => 440.0,
+            Tick::Accent => 523.25,
+            Tick::Beat | Tick::Subdivision => 523.25 / 2.0,
+        }
+    }
+
+    /// The gain relative to the volume of the phase.
+    fn gain(&self, sound: ClickSound) -> f32 {
+        match (self, sound) {
+            (Tick::Subdivision, _) => 0.5,
+            // The sample can't change its pitch, so the accents stand out by their volume
+            (Tick::Beat | Tick::Idle, ClickSound::Sample) => 0.6,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Plays the clicks of the metronome inside the engine.
+#[derive(Debug, Clone)]
+pub(super) struct Click {
+    sound: ClickSound,
+    adsr: ADSR,
+    osc: Oscillator,
+    sample: SamplePad,
+    gain: f32,
+    tick: Tick,
+    /// How long a sample lasts in seconds.
+    sample_time: f32,
+}
+
+impl Click {
+    pub fn new(sample_rate: usize, sample: SamplePad) -> Self {
+        Click {
+            sound: ClickSound::Sine,
+            adsr: ADSR::new(0.01, 0.1, 0.2, 0.02),
+            osc: Oscillator::new(Tick::Beat.freq(), sample_rate),
+            sample,
+            gain: 0.0,
+            tick: Tick::Beat,
+            sample_time: 1.0 / sample_rate as f32,
+        }
+    }
+
+    /// Play `sample` for [`ClickSound::Sample`] from now on, returning the old sample.
+    pub fn replace_sample(&mut self, sample: SamplePad) -> SamplePad {
+        std::mem::replace(&mut self.sample, sample)
+    }
+
+    pub fn set_sound(&mut self, sound: ClickSound) {
+        self.sound = sound;
+        let waveform = match sound {
+            ClickSound::Square => Waveform::Square,
+            ClickSound::Triangle => Waveform::Triangle,
+            ClickSound::Sine | ClickSound::Sample => Waveform::Sine,
+        };
+        self.osc.set_waveform(waveform);
+    }
+
+    /// Start a click of the given kind, `volume` being the volume of the phase.
+    pub fn trigger(&mut self, tick: Tick, volume: f32) {
+        self.tick = tick;
+        self.gain = volume * tick.gain(self.sound);
+        self.adsr.reset();
+        self.osc.set_freq(tick.freq());
+        if self.sound == ClickSound::Sample {
+            self.sample.restart();
+        }
+    }
+
+    /// Let the current click fade out.
+    pub fn release(&mut self) {
+        self.adsr.release();
+    }
+
+    #[cfg(test)]
+    pub fn tick(&self) -> Tick {
+        self.tick
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        if self.sound == ClickSound::Sample {
+            return self.sample.next_sample() * self.gain;
+        }
+        let envelope = self.adsr.forward(self.sample_time);
+        envelope * self.osc.increment() * self.gain
+    }
+}
+
+#[test]
+fn test_metronome_config() {
+    let mut config = MetronomeConfig::default();
+    assert!(config.is_accented(0));
+    assert!(!config.is_accented(1));
+    config.toggle_accent(2);
+    config.toggle_accent(0);
+    assert_eq!(config.describe_accents(4), "..>.");
+    // There are no accents past the longest bar
+    config.toggle_accent(TimeSignature::MAX_BEATS_PER_BAR);
+    assert!(!config.is_accented(TimeSignature::MAX_BEATS_PER_BAR));
+
+    config.adjust_volume(ClickPhase::Rolling, -10);
+    assert_eq!(config.volume(ClickPhase::Rolling), 0);
+    config.adjust_volume(ClickPhase::CountIn, 1000);
+    assert_eq!(
+        config.volume(ClickPhase::CountIn),
+        MetronomeConfig::MAX_VOLUME
+    );
+    assert_eq!(Subdivision::Triplets.next(), Subdivision::None);
+}
@@ -0,0 +1,237 @@
+/// A level of one of the two output buses.
+///
+/// The main bus carries the input, the pads and the loops, but never the metronome. The cue bus
+/// (for the headphones) mixes the metronome, the input and the loops with levels of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// The whole main bus.
+    Main,
+    /// The metronome on the cue bus.
+    CueClick,
+    /// The input on the cue bus.
+    CueMonitor,
+    /// The loops and pads on the cue bus.
+    CueLoops,
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [
+        Level::Main,
+        Level::CueClick,
+        Level::CueMonitor,
+        Level::CueLoops,
+    ];
+
+    /// The name shown in the user interface.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Main => "Main",
+            Level::CueClick => "Cue Click",
+            Level::CueMonitor => "Cue Input",
+            Level::CueLoops => "Cue Loops",
+        }
+    }
+
+    /// The next level, wrapping around.
+    pub fn next(&self) -> Level {
+        let index = Self::ALL
+            .iter()
+            .position(|level| level == self)
+            .unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+/// Every [`Level`] of the buses, in percent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Levels {
+    percent: [u32; Level::ALL.len()],
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Levels {
+            percent: [100; Level::ALL.len()],
+        }
+    }
+}
+
+impl Levels {
+    pub const MAX_PERCENT: u32 = 200;
+
+    pub fn get(&self, level: Level) -> u32 {
+        self.percent[level as usize]
+    }
+
+    /// Set `level` to `percent`, at most [`Self::MAX_PERCENT`].
+    pub fn set(&mut self, level: Level, percent: u32) {
+        self.percent[level as usize] = percent.min(Self::MAX_PERCENT);
+    }
+
+    /// The factor to multiply the samples with.
+    pub fn gain(&self, level: Level) -> f32 {
+        self.get(level) as f32 / 100.0
+    }
+}
+
+/// The mixer settings of a loop, applied to its playback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopMix {
+    /// The gain in percent.
+    pub gain: u32,
+    /// The balance from -100 (left) to 100 (right).
+    pub pan: i32,
+    pub muted: bool,
+    /// Whether the loop is soloed, muting every loop which isn't.
+    pub soloed: bool,
+}
+
+impl Default for LoopMix {
+    fn default() -> Self {
+        LoopMix {
+            gain: 100,
+            pan: 0,
+            muted: false,
+            soloed: false,
+        }
+    }
+}
+
+impl LoopMix {
+    pub const MAX_GAIN: u32 = 200;
+    pub const MAX_PAN: i32 = 100;
+
+    /// Change the gain by `delta` percent.
+    pub fn adjust_gain(&mut self, delta: i32) {
+        self.gain = self.gain.saturating_add_signed(delta).min(Self::MAX_GAIN);
+    }
+
+    /// Change the balance by `delta`.
+    pub fn adjust_pan(&mut self, delta: i32) {
+        self.pan = (self.pan + delta).clamp(-Self::MAX_PAN, Self::MAX_PAN);
+    }
+
+    /// The gain of each side, with `solo` telling whether any loop is soloed.
+    ///
+    /// Panning only turns down the opposite side, so a centered loop plays at its gain.
+    pub fn side_gains(&self, solo: bool) -> [f32; 2] {
+        if self.muted || (solo && !self.soloed) {
+            return [0.0; 2];
+        }
+        let gain = self.gain as f32 / 100.0;
+        let pan = self.pan as f32 / Self::MAX_PAN as f32;
+        [gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0)]
+    }
+}
+
+/// How fast a loop is played back, tape-style: the pitch follows the speed.
+///
+/// A pass of the loop lasts its length divided by the speed, rounded to whole beats, so a loop
+/// played at half speed spans twice as many beats and stays in time with the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopSpeed {
+    /// The speed in percent of the recorded speed.
+    percent: u32,
+}
+
+impl Default for LoopSpeed {
+    fn default() -> Self {
+        LoopSpeed::NORMAL
+    }
+}
+
+impl LoopSpeed {
+    pub const NORMAL: LoopSpeed = LoopSpeed { percent: 100 };
+    pub const HALF: LoopSpeed = LoopSpeed { percent: 50 };
+    pub const DOUBLE: LoopSpeed = LoopSpeed { percent: 200 };
+    pub const MIN_PERCENT: u32 = 25;
+    pub const MAX_PERCENT: u32 = 400;
+
+    pub fn percent(&self) -> u32 {
+        self.percent
+    }
+
+    pub fn is_normal(&self) -> bool {
+        *self == Self::NORMAL
+    }
+
+    /// How many frames of the loop are played per frame of output.
+    pub fn ratio(&self) -> f64 {
+        self.percent as f64 / 100.0
+    }
+
+    /// Change the speed by `delta` percent, for varispeed.
+    pub fn adjust(&mut self, delta: i32) {
+        self.percent = self
+            .percent
+            .saturating_add_signed(delta)
+            .clamp(Self::MIN_PERCENT, Self::MAX_PERCENT);
+    }
+
+    /// The next of normal, half and double speed, a varispeed goes back to normal.
+    pub fn next_preset(&self) -> LoopSpeed {
+        match *self {
+            Self::NORMAL => Self::HALF,
+            Self::HALF => Self::DOUBLE,
+            _ => Self::NORMAL,
+        }
+    }
+
+    /// How many beats a pass of a loop `length` beats long lasts at this speed.
+    pub fn span(&self, length: u32) -> u32 {
+        if length == 0 {
+            return 0;
+        }
+        ((length * 100 + self.percent / 2) / self.percent).max(1)
+    }
+}
+
+impl std::fmt::Display for LoopSpeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "x{}", self.ratio())
+    }
+}
+
+#[test]
+fn test_loop_speed() {
+    assert_eq!(LoopSpeed::HALF.span(4), 8);
+    assert_eq!(LoopSpeed::DOUBLE.span(4), 2);
+    assert_eq!(LoopSpeed::DOUBLE.span(1), 1);
+    assert_eq!(LoopSpeed::NORMAL.span(0), 0);
+    let mut speed = LoopSpeed::NORMAL;
+    speed.adjust(-10);
+    assert_eq!(speed.span(9), 10);
+    assert_eq!(speed.to_string(), "x0.9");
+    assert_eq!(speed.next_preset(), LoopSpeed::NORMAL);
+    speed.adjust(-1000);
+    assert_eq!(speed.percent(), LoopSpeed::MIN_PERCENT);
+    assert_eq!(LoopSpeed::HALF.next_preset(), LoopSpeed::DOUBLE);
+}
+
+#[test]
+fn test_loop_mix() {
+    let mut mix = LoopMix::default();
+    assert_eq!(mix.side_gains(false), [1.0, 1.0]);
+    mix.adjust_pan(-50);
+    mix.adjust_gain(-50);
+    assert_eq!(mix.side_gains(false), [0.5, 0.25]);
+    // Soloing another loop silences this one
+    assert_eq!(mix.side_gains(true), [0.0, 0.0]);
+    mix.soloed = true;
+    mix.muted = true;
+    assert_eq!(mix.side_gains(true), [0.0, 0.0]);
+    mix.adjust_pan(-1000);
+    assert_eq!(mix.pan, -LoopMix::MAX_PAN);
+}
+
+#[test]
+fn test_levels() {
+    let mut levels = Levels::default();
+    assert_eq!(levels.gain(Level::CueClick), 1.0);
+    levels.set(Level::CueClick, 50);
+    assert_eq!(levels.gain(Level::CueClick), 0.5);
+    assert_eq!(levels.gain(Level::Main), 1.0);
+    levels.set(Level::Main, 1000);
+    assert_eq!(levels.get(Level::Main), Levels::MAX_PERCENT);
+    assert_eq!(Level::CueLoops.next(), Level::Main);
+}
@@ -1,35 +1,201 @@
+use crate::filter::{AutoTuneConfig, Transpose};
 use color_eyre::Result;
+use color_eyre::eyre::bail;
 use jack::PortFlags;
-use std::sync::{
-    Arc,
-    atomic::{AtomicBool, AtomicU32},
-};
+use sample::SamplePad;
+use std::path::PathBuf;
+use std::sync::{Arc, atomic::AtomicU32};
 use tokio::sync::mpsc;
 
 #[derive(Debug)]
 pub struct AudioState {
-    pub enabled: Arc<AtomicBool>,                     // Main -> Audio
-    pub countin: Arc<AtomicBool>,                     // Main -> Audio
-    pub countin_length: Arc<AtomicU32>,               // Main -> Audio
-    pub started_rolling: mpsc::UnboundedReceiver<()>, // Audio -> Main
-    pub mbpm: Arc<AtomicU32>,                         // Main -> Audio
-    pub messages: mpsc::UnboundedReceiver<String>,    // Audio -> Main
-    pub loop_length: Vec<Arc<AtomicU32>>,             // Main -> Audio
-    pub loop_starting: Vec<Arc<AtomicBool>>,          // Main -> Audio
-    pub loop_layering: Vec<Arc<AtomicBool>>,          // Main -> Audio
-    pub loop_playing: Vec<Arc<AtomicBool>>,           // Audio -> Main
-    pub loop_recording: Vec<Arc<AtomicBool>>,         // Audio -> Main
-    pub current_millibeat: Arc<AtomicU32>,            // Audio -> Main
-    pub pad_tx: mpsc::UnboundedSender<usize>,
+    pub commands: QueueSender<EngineCommand>, // Main -> Audio
+    pub events: QueueReceiver<EngineEvent>,   // Audio -> Main
+    pub messages: mpsc::UnboundedReceiver<String>, // JACK -> Main
+    pub current_millibeat: Arc<AtomicU32>,    // Audio -> Main
+    pub meters: Arc<Meters>,                  // Audio -> Main
+    /// The input and output channels of the audio engine.
+    pub channels: ChannelConfig,
+    /// The time signature last sent to the audio engine.
+    pub time_signature: TimeSignature,
+    /// The metronome settings last sent to the audio engine.
+    pub metronome: MetronomeConfig,
+    /// The bus levels last sent to the audio engine.
+    pub levels: Levels,
+    /// The pitch shift of the monitored input last sent to the audio engine.
+    pub monitor_transpose: Transpose,
+    /// The pitch correction last sent to the audio engine.
+    pub auto_tune: AutoTuneConfig,
+    /// The memory for the loops of the audio engine.
+    pub buffers: LoopBufferPool,
+    /// The WAV file last sent as the click sample, `None` for the built-in one.
+    pub click_file: Option<PathBuf>,
+    click_samples: QueueSender<SamplePad>,
+    returned_samples: QueueReceiver<SamplePad>,
+}
+
+impl AudioState {
+    /// Send a command to the audio engine.
+    ///
+    /// Commands are dropped if the engine falls too far behind, see [`Self::dropped_messages`].
+    pub fn send(&mut self, command: EngineCommand) {
+        let _ = self.commands.send(command);
+    }
+
+    /// Change the time signature of the audio engine.
+    pub fn set_time_signature(&mut self, time_signature: TimeSignature) {
+        self.time_signature = time_signature;
+        self.send(EngineCommand::SetTimeSignature(time_signature));
+    }
+
+    /// Change the metronome settings of the audio engine.
+    pub fn set_metronome(&mut self, metronome: MetronomeConfig) {
+        self.metronome = metronome;
+        self.send(EngineCommand::SetMetronome(metronome));
+    }
+
+    /// Load the WAV file at `path` and make it the sample of [`ClickSound::Sample`].
+    ///
+    /// The file is decoded here, as the audio thread can't read files or allocate.
+    pub fn set_click_file(&mut self, path: PathBuf) -> Result<()> {
+        let sample = SamplePad::load_from_wav(&path)?;
+        self.collect_samples();
+        if self.click_samples.send(sample).is_err() {
+            bail!("the audio engine isn't taking new click samples");
+        }
+        self.click_file = Some(path);
+        Ok(())
+    }
+
+    /// Free the click samples the engine doesn't use anymore.
+    fn collect_samples(&mut self) {
+        while self.returned_samples.try_recv().is_some() {}
+    }
+
+    /// Change the pitch correction of the audio engine.
+    pub fn set_auto_tune(&mut self, auto_tune: AutoTuneConfig) {
+        self.auto_tune = auto_tune;
+        self.send(EngineCommand::SetAutoTune(auto_tune));
+    }
+
+    /// Change the pitch shift of the monitored input.
+    pub fn set_monitor_transpose(&mut self, transpose: Transpose) {
+        self.monitor_transpose = transpose;
+        self.send(EngineCommand::SetMonitorTranspose(transpose));
+    }
+
+    /// Change a bus level of the audio engine by `delta` percent.
+    pub fn adjust_level(&mut self, level: Level, delta: i32) {
+        let percent = self.levels.get(level).saturating_add_signed(delta);
+        self.levels.set(level, percent);
+        self.send(EngineCommand::SetLevel(level, self.levels.get(level)));
+    }
+
+    /// Take the oldest event sent by the audio engine, if any.
+    ///
+    /// This also frees the loop buffers and click samples the engine is done with.
+    pub fn try_recv(&mut self) -> Option<EngineEvent> {
+        self.buffers.collect();
+        self.collect_samples();
+        self.events.try_recv()
+    }
+
+    /// How many messages between the main thread and the audio thread were dropped because
+    /// their queue was full.
+    pub fn dropped_messages(&self) -> usize {
+        self.commands.dropped() + self.events.dropped()
+    }
 }
 
 mod adsr;
 mod callback;
+mod channels;
+mod clock;
+mod engine;
+mod meter;
+mod metronome;
+mod mix;
 mod notifications;
 mod oscillator;
+mod pool;
+mod protocol;
+mod queue;
 mod sample;
+mod stretch;
+mod time_signature;
+pub use channels::{ALL_INPUTS, ChannelConfig, InputFrame, InputMask, LoopSource, Stereo};
+pub use engine::{AudioEngine, AudioEngineSettings, LOOP_COUNT};
+pub use meter::{METER_COUNT, MeterSource, Meters};
+pub use metronome::{ClickMute, ClickPhase, ClickSound, MetronomeConfig, Subdivision};
+pub use mix::{Level, Levels, LoopMix, LoopSpeed};
+pub use notifications::Notifications;
+pub use pool::{LOOP_MEMORY_BUDGET, LoopBuffer, LoopBufferPool};
+pub use protocol::{EngineCommand, EngineEvent};
+pub use queue::{QueueReceiver, QueueSender, queue};
+pub use time_signature::TimeSignature;
+
+/// Create an [`AudioEngine`] running at `sample_rate` with `channels` together with the
+/// [`AudioState`] used to control it, without touching JACK.
+///
+/// The returned [`Notifications`] handler reports JACK messages and xruns to them.
+pub fn engine_setup(
+    sample_rate: usize,
+    channels: ChannelConfig,
+) -> (AudioEngine, AudioState, Notifications) {
+    let (command_tx, command_rx) = queue(256);
+    let (event_tx, event_rx) = queue(256);
+    let (buffer_tx, buffer_rx) = queue(16);
+    let (returned_tx, returned_rx) = queue(16);
+    let (sample_tx, sample_rx) = queue(4);
+    let (returned_sample_tx, returned_sample_rx) = queue(4);
+    let (message_tx, message_rx) = tokio::sync::mpsc::unbounded_channel();
+    let current_millibeat = Arc::new(AtomicU32::new(0));
+    let meters = Arc::new(Meters::default());
+    let xruns = Arc::new(AtomicU32::new(0));
+
+    let engine = AudioEngine::new(AudioEngineSettings {
+        sample_rate,
+        channels,
+        commands: command_rx,
+        events: event_tx,
+        buffers: buffer_rx,
+        returned_buffers: returned_tx,
+        click_samples: sample_rx,
+        returned_samples: returned_sample_tx,
+        current_millibeat: current_millibeat.clone(),
+        meters: meters.clone(),
+        xruns: xruns.clone(),
+    });
+
+    let state = AudioState {
+        commands: command_tx,
+        events: event_rx,
+        messages: message_rx,
+        current_millibeat,
+        meters,
+        channels,
+        time_signature: TimeSignature::default(),
+        metronome: MetronomeConfig::default(),
+        levels: Levels::default(),
+        monitor_transpose: Transpose::default(),
+        auto_tune: AutoTuneConfig::default(),
+        buffers: LoopBufferPool::new(sample_rate, buffer_tx, returned_rx),
+        click_file: None,
+        click_samples: sample_tx,
+        returned_samples: returned_sample_rx,
+    };
+    let notifications = Notifications {
+        tx: message_tx,
+        xruns,
+    };
+    (engine, state, notifications)
+}
 
-pub fn audio_setup() -> Result<(
+/// Register the JACK ports for `channels`, start the [`AudioEngine`] and connect the ports to
+/// the physical ones.
+pub fn audio_setup(
+    channels: ChannelConfig,
+) -> Result<(
     jack::AsyncClient<impl jack::NotificationHandler, impl jack::ProcessHandler>,
     AudioState,
 )> {
@@ -38,42 +204,23 @@ pub fn audio_setup() -> Result<(
 
     let (client, _status) = jack::Client::new("loooper", jack::ClientOptions::default())?;
 
-    let in_port = client.register_port("loooper_in", jack::AudioIn::default())?;
-    let out_port = client.register_port("loooper_out", jack::AudioOut::default())?;
-
-    let enabled = Arc::new(AtomicBool::new(false));
-    let countin = Arc::new(AtomicBool::new(false));
-    let countin_length = Arc::new(AtomicU32::new(0));
-    let (rolling_tx, rolling_rx) = tokio::sync::mpsc::unbounded_channel();
-    let mbpm = Arc::new(AtomicU32::new(120));
-    let (message_tx, message_rx) = tokio::sync::mpsc::unbounded_channel();
-    let loop_length: Vec<_> = (0..8).map(|_| Arc::from(AtomicU32::new(4))).collect();
-    let loop_starting: Vec<_> = (0..8).map(|_| Arc::from(AtomicBool::new(false))).collect();
-    let loop_layering: Vec<_> = (0..8).map(|_| Arc::from(AtomicBool::new(false))).collect();
-    let loop_playing: Vec<_> = (0..8).map(|_| Arc::from(AtomicBool::new(false))).collect();
-    let loop_recording: Vec<_> = (0..8).map(|_| Arc::from(AtomicBool::new(false))).collect();
-    let current_millibeat = Arc::new(AtomicU32::new(0));
-    let (pad_tx, pad_rx) = tokio::sync::mpsc::unbounded_channel::<usize>();
+    let in_names = ChannelConfig::port_names("loooper_in", channels.inputs);
+    let out_names = ChannelConfig::port_names("loooper_out", channels.outputs);
+    let cue_names = ChannelConfig::port_names("loooper_cue", channels.cue_channels());
+    let in_ports = in_names
+        .iter()
+        .map(|name| client.register_port(name, jack::AudioIn::default()))
+        .collect::<Result<Vec<_>, _>>()?;
+    // The stem ports are left for the user to connect
+    let out_ports = out_names
+        .iter()
+        .chain(&cue_names)
+        .chain(&channels.stem_port_names())
+        .map(|name| client.register_port(name, jack::AudioOut::default()))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let notification_handler = notifications::Notifications {
-        tx: message_tx.clone(),
-    };
-    let callback_handler = callback::create_callback(callback::AudioCallbackSettings {
-        sample_rate: client.sample_rate(),
-        in_port,
-        out_port,
-        enabled: enabled.clone(),
-        countin: countin.clone(),
-        countin_length: countin_length.clone(),
-        rolling_tx,
-        mbpm: mbpm.clone(),
-        loop_length: loop_length.clone(),
-        loop_starting: loop_starting.clone(),
-        loop_playing: loop_playing.clone(),
-        loop_recording: loop_recording.clone(),
-        current_millibeat: current_millibeat.clone(),
-        pad_rx,
-    });
+    let (engine, state, notification_handler) = engine_setup(client.sample_rate(), channels);
+    let callback_handler = callback::create_callback(engine, in_ports, out_ports);
     let active_client = client.activate_async(notification_handler, callback_handler)?;
 
     {
@@ -82,10 +229,10 @@ pub fn audio_setup() -> Result<(
             Some("32 bit float mono audio"),
             PortFlags::IS_OUTPUT.union(PortFlags::IS_PHYSICAL),
         );
-        if let Some(port) = src_ports.first() {
+        for (port, name) in src_ports.iter().zip(&in_names) {
             active_client
                 .as_client()
-                .connect_ports_by_name(port.as_str(), "loooper:loooper_in")
+                .connect_ports_by_name(port.as_str(), &format!("loooper:{}", name))
                 .unwrap();
         }
 
@@ -94,35 +241,28 @@ pub fn audio_setup() -> Result<(
             Some("32 bit float mono audio"),
             PortFlags::IS_INPUT.union(PortFlags::IS_PHYSICAL),
         );
-        for port in &dest_ports {
-            active_client
-                .as_client()
-                .connect_ports_by_name("loooper:loooper_out", port.as_str())
-                .unwrap();
+        // The main bus takes the first physical ports and the cue bus the ones after it, a mono
+        // bus is sent to a pair of ports
+        let mut dest_ports = dest_ports.iter();
+        for names in [&out_names, &cue_names] {
+            let port_count = names.len().max(2);
+            for (index, port) in dest_ports.by_ref().take(port_count).enumerate() {
+                let name = &names[index % names.len()];
+                active_client
+                    .as_client()
+                    .connect_ports_by_name(&format!("loooper:{}", name), port.as_str())
+                    .unwrap();
+            }
         }
     }
 
-    let state = AudioState {
-        enabled,
-        countin,
-        countin_length,
-        started_rolling: rolling_rx,
-        mbpm,
-        messages: message_rx,
-        loop_length,
-        loop_starting,
-        loop_layering,
-        loop_playing,
-        loop_recording,
-        current_millibeat,
-        pad_tx,
-    };
     Ok((active_client, state))
 }
 
 #[test]
+#[ignore = "requires a running JACK server"]
 fn test_host_device_setup() {
-    let result = audio_setup();
+    let result = audio_setup(ChannelConfig::default());
     assert!(result.is_ok());
     let _ = result.unwrap();
 }
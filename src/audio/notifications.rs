@@ -1,8 +1,12 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use tokio::sync::mpsc;
 
 // Taken from https://github.com/RustAudio/rust-jack/blob/main/examples/playback_capture.rs
 pub struct Notifications {
     pub tx: mpsc::UnboundedSender<String>,
+    /// Shared with the audio engine, which reports xruns as events
+    pub xruns: Arc<AtomicU32>,
 }
 
 impl jack::NotificationHandler for Notifications {
@@ -81,6 +85,7 @@ impl jack::NotificationHandler for Notifications {
     }
 
     fn xrun(&mut self, _: &jack::Client) -> jack::Control {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
         let _ = self.tx.send("JACK: xrun occurred".to_string());
         jack::Control::Continue
     }
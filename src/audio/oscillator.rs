@@ -1,6 +1,16 @@
+/// The shape of the wave an [`Oscillator`] produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Square,
+    Triangle,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Oscillator {
     freq: f32,
+    waveform: Waveform,
     increment_time: f32,
     phase: f32,
     phase_increment: f32,
@@ -11,6 +21,7 @@ impl Oscillator {
         let increment_time = 1.0 / sample_rate as f32;
         Oscillator {
             freq,
+            waveform: Waveform::Sine,
             increment_time,
             phase: 0.0,
             phase_increment: std::f32::consts::TAU * freq * increment_time,
@@ -24,6 +35,12 @@ impl Oscillator {
         self
     }
 
+    #[inline]
+    pub fn set_waveform(&mut self, waveform: Waveform) -> &mut Self {
+        self.waveform = waveform;
+        self
+    }
+
     #[inline]
     pub fn increment(&mut self) -> f32 {
         self.phase += self.phase_increment;
@@ -33,6 +50,20 @@ impl Oscillator {
 
     #[inline]
     pub fn level(&self) -> f32 {
-        self.phase.sin()
+        match self.waveform {
+            Waveform::Sine => self.phase.sin(),
+            Waveform::Square => {
+                if self.phase < std::f32::consts::PI {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            // Starts at 0 and peaks a quarter into the cycle, just like the sine
+            Waveform::Triangle => {
+                let x = self.phase / std::f32::consts::TAU;
+                1.0 - 4.0 * (x - 0.25).abs().min((x - 1.25).abs())
+            }
+        }
     }
 }
@@ -0,0 +1,146 @@
+use super::clock::samples_per_beat;
+use super::engine::LOOP_COUNT;
+use super::queue::{QueueReceiver, QueueSender};
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+
+/// How many bytes of loop audio we allow ourselves to keep around, see PLAN.md.
+pub const LOOP_MEMORY_BUDGET: usize = 100_000_000;
+
+/// How long a freely recorded loop may get, as its length isn't known in advance.
+const FREE_RECORDING_SECONDS: usize = 60;
+
+/// A buffer for the loop at `index`, replacing the one the engine held before.
+pub struct LoopBuffer {
+    pub index: usize,
+    /// How many channels are interleaved in the samples.
+    pub channels: usize,
+    pub samples: Box<[f32]>,
+}
+
+/// Hands loop buffers to the [`AudioEngine`](super::AudioEngine) while keeping the total memory
+/// used within [`LOOP_MEMORY_BUDGET`].
+///
+/// Buffers are allocated here, on the main thread, since the audio thread can't allocate.
+/// The buffers replaced by the engine are sent back and freed by [`Self::collect`] for the same
+/// reason.
+pub struct LoopBufferPool {
+    sample_rate: usize,
+    /// The budget in samples.
+    budget: usize,
+    /// How many samples the engine holds for each loop.
+    allocated: [usize; LOOP_COUNT],
+    /// How many channels the buffer of each loop holds.
+    channels: [usize; LOOP_COUNT],
+    buffers: QueueSender<LoopBuffer>,
+    returned: QueueReceiver<Box<[f32]>>,
+}
+
+impl LoopBufferPool {
+    pub fn new(
+        sample_rate: usize,
+        buffers: QueueSender<LoopBuffer>,
+        returned: QueueReceiver<Box<[f32]>>,
+    ) -> Self {
+        LoopBufferPool {
+            sample_rate,
+            budget: LOOP_MEMORY_BUDGET / size_of::<f32>(),
+            allocated: [0; LOOP_COUNT],
+            channels: [1; LOOP_COUNT],
+            buffers,
+            returned,
+        }
+    }
+
+    /// How many samples a loop of `beats` beats with `channels` channels needs at `mbpm`
+    /// (BPM * 1000).
+    ///
+    /// There's an extra beat of room, as the tempo found by a free recording might be slightly
+    /// off from `mbpm`.
+    pub fn samples_for(&self, beats: u32, mbpm: u32, channels: usize) -> usize {
+        (beats as usize + 1) * samples_per_beat(self.sample_rate, mbpm) as usize * channels
+    }
+
+    /// How many samples a freely recorded loop with `channels` channels gets.
+    pub fn free_recording_samples(&self, channels: usize) -> usize {
+        FREE_RECORDING_SECONDS * self.sample_rate * channels
+    }
+
+    /// How many samples the whole budget holds.
+    pub fn total_samples(&self) -> usize {
+        self.budget
+    }
+
+    /// How many samples the engine holds for the loop at `index`.
+    pub fn allocated(&self, index: usize) -> usize {
+        self.allocated.get(index).copied().unwrap_or(0)
+    }
+
+    /// How many samples of the budget aren't handed to the engine yet.
+    pub fn remaining_samples(&self) -> usize {
+        self.budget - self.allocated.iter().sum::<usize>()
+    }
+
+    /// How many seconds of mono audio fit into `samples` samples, stereo takes twice as many.
+    pub fn seconds(&self, samples: usize) -> f32 {
+        samples as f32 / self.sample_rate as f32
+    }
+
+    /// Hand the engine a buffer of `samples` samples for the loop at `index`, which records
+    /// `channels` channels.
+    ///
+    /// The old buffer of the loop is replaced, throwing away whatever was recorded into it.
+    /// Buffers of the right size are kept as they are.
+    pub fn provide(&mut self, index: usize, channels: usize, samples: usize) -> Result<()> {
+        if index >= LOOP_COUNT {
+            bail!("there's no loop {}", index + 1);
+        }
+        let channels = channels.max(1);
+        if self.allocated[index] == samples && self.channels[index] == channels {
+            return Ok(());
+        }
+        let available = self.remaining_samples() + self.allocated[index];
+        if samples > available {
+            bail!(
+                "loop {} needs {:.1}s of audio, but only {:.1}s are left",
+                index + 1,
+                self.seconds(samples / channels),
+                self.seconds(available / channels)
+            );
+        }
+        self.collect();
+        if self
+            .buffers
+            .send(LoopBuffer {
+                index,
+                channels,
+                samples: vec![0.0; samples].into_boxed_slice(),
+            })
+            .is_err()
+        {
+            bail!("the audio engine isn't taking new loop buffers");
+        }
+        self.allocated[index] = samples;
+        self.channels[index] = channels;
+        Ok(())
+    }
+
+    /// Give the memory of the loop at `index` back to the pool.
+    pub fn release(&mut self, index: usize) -> Result<()> {
+        self.provide(index, self.channels[index], 0)
+    }
+
+    /// Free the buffers the engine doesn't use anymore.
+    pub fn collect(&mut self) {
+        while self.returned.try_recv().is_some() {}
+    }
+}
+
+impl std::fmt::Debug for LoopBufferPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoopBufferPool")
+            .field("budget", &self.budget)
+            .field("allocated", &self.allocated)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,118 @@
+use super::{Level, LoopMix, LoopSource, LoopSpeed, MetronomeConfig, TimeSignature};
+use crate::filter::{AutoTuneConfig, Transpose};
+
+/// Commands sent from the main thread to the [`AudioEngine`](super::AudioEngine).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineCommand {
+    /// Start producing sound, the metronome starts clicking right away.
+    Enable,
+    /// Stop producing sound and reset the transport and every loop.
+    Disable,
+    /// Change the beats per minute (BPM) * 1000, applied at the next beat.
+    ///
+    /// Loops recorded at another tempo are time-stretched to follow it, without changing their
+    /// pitch.
+    SetTempo(u32),
+    /// Change the tempo gradually to `mbpm` (BPM * 1000), a step at each of the next `beats`
+    /// beats. Every step is reported with [`EngineEvent::TempoChanged`].
+    RampTempo { mbpm: u32, beats: u32 },
+    /// Change the time signature, which decides the accents and where loops may start.
+    SetTimeSignature(TimeSignature),
+    /// Change the metronome settings, applied from the next beat on.
+    SetMetronome(MetronomeConfig),
+    /// Change a level of the main or the cue bus, in percent, applied right away.
+    SetLevel(Level, u32),
+    /// Start a count-in of the given amount of beats at the next beat.
+    StartCountIn(u32),
+    /// Choose whether loops starting with the rolling phase record the last bar of the count-in
+    /// as well, so a pickup isn't lost. The pre-roll is mixed into the end of the loop.
+    SetPreRoll(bool),
+    /// Start recording a loop right away, without a tempo or a count-in.
+    ///
+    /// The loop is recorded until [`EngineCommand::FinishFreeRecording`], the tempo is then
+    /// derived from the recorded length and the configured length (in beats) of the loop.
+    StartFreeRecording(usize),
+    /// Stop the free recording, set the tempo and start rolling right away.
+    FinishFreeRecording,
+    /// Set the length (in beats) of a loop and whether new takes are layered on top of old ones.
+    ConfigureLoop {
+        index: usize,
+        length: u32,
+        layering: bool,
+    },
+    /// Choose what a loop records, applied right away.
+    SetLoopSource { index: usize, source: LoopSource },
+    /// Change the mixer settings of a loop, faded in right away.
+    SetLoopMix { index: usize, mix: LoopMix },
+    /// Change the pitch correction of an input, applied right away. The correction is heard on
+    /// the monitor and recorded into the loops unless the config says otherwise.
+    SetAutoTune(AutoTuneConfig),
+    /// Shift the pitch of the monitored input, applied right away.
+    SetMonitorTranspose(Transpose),
+    /// Shift the pitch of a loop's playback, applied right away. The recording is kept as it is.
+    SetLoopTranspose { index: usize, transpose: Transpose },
+    /// Change how fast a loop plays, applied right away. The loop restarts at the boundaries of
+    /// its new span from then on.
+    SetLoopSpeed { index: usize, speed: LoopSpeed },
+    /// Play a loop backwards or forwards again, crossfaded right away. It stays aligned to its
+    /// boundaries, a reversed pass starts at the end of the recording.
+    SetLoopReverse { index: usize, reversed: bool },
+    /// Start a loop at its next boundary, recording it first if it's still empty.
+    ArmLoop(usize),
+    /// Stop a loop at its next boundary.
+    StopLoop(usize),
+    /// Record a single pass into a loop from its next boundary on, then keep playing it.
+    ///
+    /// The new take is layered on top of the old one if the loop is layering, otherwise it
+    /// replaces it.
+    RecordLoop(usize),
+    /// Withdraw a [`EngineCommand::RecordLoop`] that didn't start yet.
+    CancelRecording(usize),
+    /// Throw away the content of a loop at its next boundary.
+    ClearLoop(usize),
+    /// Start playing a sample pad right away.
+    TriggerPad(usize),
+}
+
+/// Events sent from the [`AudioEngine`](super::AudioEngine) back to the main thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineEvent {
+    /// The count-in ended and the loops started rolling.
+    StartedRolling,
+    /// The tempo was changed by the engine itself, in BPM * 1000.
+    TempoChanged(u32),
+    /// A new beat started, counting from 1 at the start of the count-in and again when rolling.
+    BeatTick(u32),
+    /// A loop started capturing the input.
+    LoopStartedRecording(usize),
+    /// A loop finished capturing the input.
+    LoopFilled(usize),
+    /// A loop started playing back its content.
+    LoopStartedPlaying(usize),
+    /// A loop stopped playing back its content.
+    LoopStopped(usize),
+    /// The content of a loop was thrown away.
+    LoopCleared(usize),
+    /// A free recording was too short to derive a tempo from and was thrown away.
+    RecordingCancelled(usize),
+    /// JACK reported an xrun (buffer under- or overrun).
+    Xrun,
+    /// The given amount of samples on the main or cue bus exceeded full scale during a block and
+    /// were caught by the limiter.
+    Clipped(u32),
+}
+
+impl EngineEvent {
+    /// The index of the loop this event is about, if any.
+    pub fn loop_index(&self) -> Option<usize> {
+        match *self {
+            EngineEvent::LoopStartedRecording(index)
+            | EngineEvent::LoopFilled(index)
+            | EngineEvent::LoopStartedPlaying(index)
+            | EngineEvent::LoopStopped(index)
+            | EngineEvent::LoopCleared(index)
+            | EngineEvent::RecordingCancelled(index) => Some(index),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The sending half of a [`queue`].
+///
+/// Sending never blocks nor allocates, so it is safe to use inside the audio callback.
+pub struct QueueSender<T> {
+    producer: HeapProd<T>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// The receiving half of a [`queue`].
+///
+/// Receiving never blocks nor allocates, so it is safe to use inside the audio callback.
+pub struct QueueReceiver<T> {
+    consumer: HeapCons<T>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Create a preallocated, lock-free single-producer single-consumer queue holding at most
+/// `capacity` items.
+///
+/// Items sent while the queue is full are dropped and counted, see [`QueueSender::dropped`].
+pub fn queue<T>(capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (producer, consumer) = HeapRb::new(capacity).split();
+    let dropped = Arc::new(AtomicUsize::new(0));
+    (
+        QueueSender {
+            producer,
+            dropped: dropped.clone(),
+        },
+        QueueReceiver { consumer, dropped },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Try to send an item, giving it back if the queue is full.
+    pub fn send(&mut self, item: T) -> Result<(), T> {
+        self.producer.try_push(item).inspect_err(|_| {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    /// How many items were dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the next item sent would be dropped.
+    pub fn is_full(&self) -> bool {
+        self.producer.is_full()
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Take the oldest item out of the queue, if any.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.consumer.try_pop()
+    }
+
+    /// How many items were dropped by the sender because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> std::fmt::Debug for QueueSender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueSender")
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> std::fmt::Debug for QueueReceiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueReceiver")
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+#[test]
+fn test_queue_overflow() {
+    let (mut tx, mut rx) = queue(2);
+    assert!(tx.send(1).is_ok());
+    assert!(tx.send(2).is_ok());
+    assert!(tx.is_full());
+    assert_eq!(tx.send(3), Err(3));
+    assert_eq!(tx.dropped(), 1);
+    assert_eq!(rx.dropped(), 1);
+
+    assert_eq!(rx.try_recv(), Some(1));
+    assert!(tx.send(4).is_ok());
+    assert_eq!(rx.try_recv(), Some(2));
+    assert_eq!(rx.try_recv(), Some(4));
+    assert_eq!(rx.try_recv(), None);
+}
@@ -1,3 +1,4 @@
+use color_eyre::eyre::bail;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -17,28 +18,36 @@ impl SamplePad {
         }
     }
 
+    pub fn from_samples(samples: Vec<f32>) -> Self {
+        SamplePad {
+            buffer: samples.into(),
+            pos: 0,
+            playing: false,
+        }
+    }
+
     pub fn load_from_wav(path: &Path) -> color_eyre::Result<Self> {
         let mut reader = hound::WavReader::open(path)?;
         let spec = reader.spec();
-        assert!(
-            spec.channels == 1,
-            "Only mono samples are supported, found {} channels",
-            spec.channels
-        );
-        assert!(
-            spec.sample_format == hound::SampleFormat::Int,
-            "Only integer samples are supported, found {:?}",
-            spec.sample_format
-        );
-        let buffer = reader
+        // The file may be picked by the user, so a wrong format is an error rather than a panic
+        if spec.channels != 1 {
+            bail!(
+                "only mono samples are supported, found {} channels",
+                spec.channels
+            );
+        }
+        if spec.sample_format != hound::SampleFormat::Int || spec.bits_per_sample != 16 {
+            bail!(
+                "only 16 bit integer samples are supported, found {} bit {:?}",
+                spec.bits_per_sample,
+                spec.sample_format
+            );
+        }
+        let samples = reader
             .samples::<i16>()
-            .map(|s| s.unwrap() as f32 / i16::MAX as f32)
-            .collect();
-        Ok(SamplePad {
-            buffer,
-            pos: 0,
-            playing: false,
-        })
+            .map(|s| s.map(|s| s as f32 / i16::MAX as f32))
+            .collect::<Result<_, _>>()?;
+        Ok(Self::from_samples(samples))
     }
 
     pub fn start(&mut self) {
@@ -48,6 +57,12 @@ impl SamplePad {
         }
     }
 
+    /// Play from the start, even if the sample is still playing.
+    pub fn restart(&mut self) {
+        self.pos = 0;
+        self.playing = !self.buffer.is_empty();
+    }
+
     pub fn ended(&self) -> bool {
         !self.playing
     }
@@ -0,0 +1,212 @@
+use super::channels::Stereo;
+
+/// How long a grain lasts.
+const GRAIN_MS: usize = 40;
+/// How far a grain may be moved from where it should start, to continue the previous one.
+const SEARCH_MS: usize = 8;
+/// Only every so many samples are compared while searching, to keep it cheap.
+const SEARCH_STEP: usize = 4;
+
+/// A grain of the loop, played through the window.
+#[derive(Debug, Clone, Copy, Default)]
+struct Grain {
+    /// The frame of the loop the grain starts at.
+    start: usize,
+    /// How many frames of the grain were played.
+    age: usize,
+    /// Whether the grain plays backwards from its start.
+    reversed: bool,
+}
+
+impl Grain {
+    /// The frame of a loop of `frames` frames `offset` frames into the grain.
+    fn frame(&self, offset: usize, frames: usize) -> usize {
+        frame_at(self.start, offset, self.reversed, frames)
+    }
+}
+
+/// The frame `offset` frames after `start` in a loop of `frames` frames, or before it if
+/// `reversed`.
+fn frame_at(start: usize, offset: usize, reversed: bool, frames: usize) -> usize {
+    if reversed {
+        (start + frames - offset % frames) % frames
+    } else {
+        (start + offset) % frames
+    }
+}
+
+/// Plays a loop faster or slower without changing its pitch, using WSOLA (waveform similarity
+/// overlap-add).
+///
+/// The loop is cut into overlapping grains which are faded in and out with a Hann window. Every
+/// grain starts where the loop is at that time, moved a little so it lines up with the end of
+/// the previous grain, which avoids the phasing of a plain overlap-add. The loop is complete, so
+/// the grains can look ahead without adding latency.
+#[derive(Debug, Clone)]
+pub(super) struct Stretcher {
+    window: Box<[f32]>,
+    /// How many frames apart the grains start, half a grain.
+    hop: usize,
+    search: usize,
+    grains: [Option<Grain>; 2],
+    /// Where the loop is, in frames.
+    position: f64,
+    /// How many frames until the next grain starts.
+    next_grain: usize,
+}
+
+impl Stretcher {
+    pub fn new(sample_rate: usize) -> Self {
+        let hop = (sample_rate * GRAIN_MS / 2000).max(SEARCH_STEP);
+        let length = 2 * hop;
+        // A periodic Hann window, two of them half a grain apart add up to one
+        let window = (0..length)
+            .map(|n| 0.5 - 0.5 * (std::f32::consts::TAU * n as f32 / length as f32).cos())
+            .collect();
+        Stretcher {
+            window,
+            hop,
+            search: sample_rate * SEARCH_MS / 1000,
+            grains: [None; 2],
+            position: 0.0,
+            next_grain: 0,
+        }
+    }
+
+    /// Start over at `position` (in frames), fading in.
+    pub fn reset(&mut self, position: f64) {
+        self.grains = [None; 2];
+        self.position = position;
+        self.next_grain = 0;
+    }
+
+    /// Move to `position` (in frames) without interrupting the grains playing.
+    pub fn seek(&mut self, position: f64) {
+        self.position = position;
+    }
+
+    /// The next frame of the loop in `buffer`, which holds `frames` frames of `channels`
+    /// channels, advancing `speed` frames of the loop. A negative speed plays it backwards, the
+    /// grains already playing fade out in their own direction.
+    pub fn next_frame(
+        &mut self,
+        buffer: &[f32],
+        channels: usize,
+        frames: usize,
+        speed: f64,
+    ) -> Stereo {
+        let frames = frames.min(buffer.len() / channels.max(1));
+        if frames == 0 {
+            return [0.0; 2];
+        }
+        if self.next_grain == 0 {
+            self.start_grain(buffer, channels, frames, speed < 0.0);
+            self.next_grain = self.hop;
+        }
+        self.next_grain -= 1;
+
+        let mut frame = [0.0; 2];
+        for grain in self.grains.iter_mut().flatten() {
+            let Some(&gain) = self.window.get(grain.age) else {
+                continue;
+            };
+            let index = grain.frame(grain.age, frames) * channels;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                // Mono loops play on both sides
+                *sample += buffer[index + channel % channels] * gain;
+            }
+            grain.age += 1;
+        }
+        self.position = (self.position + speed).rem_euclid(frames as f64);
+        frame
+    }
+
+    fn start_grain(&mut self, buffer: &[f32], channels: usize, frames: usize, reversed: bool) {
+        let target = self.position as usize % frames;
+        let start = match self.grains[1] {
+            // Where the previous grain would naturally go on
+            Some(previous) if previous.reversed == reversed => {
+                let natural = previous.frame(self.hop, frames);
+                self.best_match(buffer, channels, frames, natural, target, reversed)
+            }
+            // The direction changed, the grains simply crossfade
+            _ => target,
+        };
+        let grain = Grain {
+            start,
+            age: 0,
+            reversed,
+        };
+        self.grains = [self.grains[1], Some(grain)];
+    }
+
+    /// The start near `target` whose first half grain looks most like the one at `natural`.
+    fn best_match(
+        &self,
+        buffer: &[f32],
+        channels: usize,
+        frames: usize,
+        natural: usize,
+        target: usize,
+        reversed: bool,
+    ) -> usize {
+        let sample =
+            |start: usize, n: usize| buffer[frame_at(start, n, reversed, frames) * channels];
+        let mut best = (f32::MIN, target);
+        let search = self.search.min(frames / 2);
+        for offset in (0..=2 * search).step_by(SEARCH_STEP) {
+            let candidate = target + frames - search + offset;
+            let correlation: f32 = (0..self.hop)
+                .step_by(SEARCH_STEP)
+                .map(|n| sample(natural, n) * sample(candidate, n))
+                .sum();
+            if correlation > best.0 {
+                best = (correlation, candidate % frames);
+            }
+        }
+        best.1
+    }
+}
+
+#[test]
+fn test_stretcher() {
+    use crate::testing::{SAMPLE_RATE, crossings, tone};
+    const FRAMES: usize = SAMPLE_RATE;
+    // A steady 100 Hz tone, which has to keep its pitch
+    let buffer = tone(100.0, 1.0);
+    let mut stretcher = Stretcher::new(SAMPLE_RATE);
+    stretcher.reset(0.0);
+    let output: Vec<f32> = (0..2 * FRAMES)
+        .map(|_| stretcher.next_frame(&buffer, 1, FRAMES, 0.5)[0])
+        .collect();
+    // Half as fast, the loop went around once
+    let position = stretcher.position;
+    assert!(position.min(FRAMES as f64 - position) < 1.0);
+
+    // Past the fade-in, the level and the pitch are kept: 100 Hz crosses zero 200 times a second
+    let settled = &output[FRAMES / 2..];
+    let peak = settled.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+    assert!(peak > 0.18 && peak < 0.22);
+    let expected = 200 * settled.len() / SAMPLE_RATE;
+    assert!(crossings(settled).abs_diff(expected) <= expected / 20);
+}
+
+#[test]
+fn test_reversed_stretcher() {
+    const SAMPLE_RATE: usize = 4800;
+    const FRAMES: usize = 4800;
+    // A rising ramp falls when played backwards
+    let buffer: Vec<f32> = (0..FRAMES).map(|n| n as f32 / FRAMES as f32).collect();
+    let mut stretcher = Stretcher::new(SAMPLE_RATE);
+    stretcher.reset(0.0);
+    let output: Vec<f32> = (0..FRAMES)
+        .map(|_| stretcher.next_frame(&buffer, 1, FRAMES, -0.5)[0])
+        .collect();
+    let position = stretcher.position;
+    assert!((position - FRAMES as f64 / 2.0).abs() < 1.0);
+    let falling = output[FRAMES / 4..]
+        .windows(2)
+        .filter(|pair| pair[1] < pair[0])
+        .count();
+    assert!(falling > FRAMES * 3 / 4 * 9 / 10, "{} falling", falling);
+}
@@ -0,0 +1,95 @@
+/// A time signature, e.g. 3/4 or 6/8.
+///
+/// The tempo (BPM) always counts `beat_unit` notes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    /// How many beats are in a bar.
+    pub beats_per_bar: u32,
+    /// Which note value a beat is, e.g. 4 for quarter notes or 8 for eighth notes.
+    pub beat_unit: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        TimeSignature {
+            beats_per_bar: 4,
+            beat_unit: 4,
+        }
+    }
+}
+
+impl TimeSignature {
+    pub const MAX_BEATS_PER_BAR: u32 = 16;
+    pub const BEAT_UNITS: [u32; 4] = [2, 4, 8, 16];
+
+    /// Whether `beat` (counting from 1) is the first beat of a bar.
+    pub fn is_downbeat(&self, beat: u32) -> bool {
+        beat.saturating_sub(1)
+            .is_multiple_of(self.beats_per_bar.max(1))
+    }
+
+    /// Split `beat` (counting from 1) into its bar and the beat inside the bar, both counting from 1.
+    pub fn bar_and_beat(&self, beat: u32) -> (u32, u32) {
+        let beats_per_bar = self.beats_per_bar.max(1);
+        let beat = beat.saturating_sub(1);
+        (beat / beats_per_bar + 1, beat % beats_per_bar + 1)
+    }
+
+    /// Switch to the next beat unit, wrapping around.
+    pub fn cycle_beat_unit(&mut self) {
+        let index = Self::BEAT_UNITS
+            .iter()
+            .position(|&unit| unit == self.beat_unit)
+            .map_or(0, |index| (index + 1) % Self::BEAT_UNITS.len());
+        self.beat_unit = Self::BEAT_UNITS[index];
+    }
+}
+
+impl std::fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.beats_per_bar, self.beat_unit)
+    }
+}
+
+impl std::str::FromStr for TimeSignature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (beats_per_bar, beat_unit) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected `<beats>/<unit>`, found `{}`", s))?;
+        let time_signature = TimeSignature {
+            beats_per_bar: beats_per_bar
+                .parse()
+                .map_err(|_| format!("invalid beat count `{}`", beats_per_bar))?,
+            beat_unit: beat_unit
+                .parse()
+                .map_err(|_| format!("invalid beat unit `{}`", beat_unit))?,
+        };
+        if !(1..=Self::MAX_BEATS_PER_BAR).contains(&time_signature.beats_per_bar) {
+            return Err(format!(
+                "a bar can have 1 to {} beats",
+                Self::MAX_BEATS_PER_BAR
+            ));
+        }
+        if !Self::BEAT_UNITS.contains(&time_signature.beat_unit) {
+            return Err(format!(
+                "unsupported beat unit {}",
+                time_signature.beat_unit
+            ));
+        }
+        Ok(time_signature)
+    }
+}
+
+#[test]
+fn test_time_signature() {
+    let seven: TimeSignature = "7/8".parse().unwrap();
+    assert!(seven.is_downbeat(8));
+    assert!(!seven.is_downbeat(7));
+    assert_eq!(seven.bar_and_beat(8), (2, 1));
+    assert_eq!(seven.bar_and_beat(7), (1, 7));
+
+    assert!("0/4".parse::<TimeSignature>().is_err());
+    assert!("4/3".parse::<TimeSignature>().is_err());
+}
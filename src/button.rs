@@ -3,9 +3,33 @@ use std::time::Duration;
 
 const BUTTON_PINS: [u8; 13] = [13, 6, 5, 22, 27, 17, 4, 16, 12, 14, 15, 24, 25];
 
+/// A button going down or coming back up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ButtonEvent {
+    pub id: usize,
+    /// Whether the button is held down, which pulls its pin low
+    pub pressed: bool,
+}
+
+/// Returns which loop a button mutes while it's held, if any
+///
+/// These buttons report both pressing and releasing them, the other ones only pressing them.
+pub fn loop_index(button_id: usize) -> Option<usize> {
+    (1..=8).contains(&button_id).then(|| button_id - 1)
+}
+
+/// Returns whether a button reverses the selected loop
+pub fn is_reverse(button_id: usize) -> bool {
+    button_id == 0
+}
+
+/// Returns which sample pad a button triggers, if any
+pub fn pad_index(button_id: usize) -> Option<usize> {
+    button_id.checked_sub(9)
+}
+
 pub fn button(
-    pad_tx: tokio::sync::mpsc::UnboundedSender<usize>,
-    button_tx: tokio::sync::mpsc::UnboundedSender<usize>,
+    button_tx: tokio::sync::mpsc::UnboundedSender<ButtonEvent>,
     mut shutdown: tokio::sync::oneshot::Receiver<()>,
 ) -> Result<()> {
     let gpio = rppal::gpio::Gpio::new()?;
@@ -27,13 +51,12 @@ pub fn button(
     let interval = Duration::from_micros(100);
 
     loop {
-        if let Some((pin, _event)) = gpio.poll_interrupts(&polled_pins, false, Some(interval))? {
+        if let Some((pin, event)) = gpio.poll_interrupts(&polled_pins, false, Some(interval))? {
             let button_id = BUTTON_PINS.iter().position(|&p| p == pin.pin()).unwrap();
-            if button_id > 8 {
-                let pad_id = button_id - 9;
-                let _ = pad_tx.send(pad_id);
-            }
-            let _ = button_tx.send(button_id);
+            let _ = button_tx.send(ButtonEvent {
+                id: button_id,
+                pressed: event.trigger == rppal::gpio::Trigger::FallingEdge,
+            });
         }
 
         if shutdown.try_recv().is_ok() {
@@ -12,12 +12,70 @@ use ratatui::{
     widgets::{Block, Paragraph, Widget},
 };
 
-use crate::{audio::AudioState, loops::LoopState};
+use crate::{
+    audio::{AudioState, EngineCommand, EngineEvent, TimeSignature},
+    button::ButtonEvent,
+    loops::{self, LoopState},
+};
+
+/// How long the count-in lasts, zero for none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CountInLength {
+    Bars(u32),
+    Beats(u32),
+}
+
+impl Default for CountInLength {
+    fn default() -> Self {
+        CountInLength::Bars(2)
+    }
+}
+
+impl CountInLength {
+    /// The longest count-in, in bars or beats.
+    pub const MAX: u32 = 16;
+
+    /// The length in beats with `time_signature`.
+    pub fn beats(&self, time_signature: TimeSignature) -> u32 {
+        match *self {
+            CountInLength::Bars(bars) => bars * time_signature.beats_per_bar,
+            CountInLength::Beats(beats) => beats,
+        }
+    }
+
+    /// Change the length by `delta` bars or beats.
+    pub fn adjust(&mut self, delta: i32) {
+        let (CountInLength::Bars(count) | CountInLength::Beats(count)) = self;
+        *count = count.saturating_add_signed(delta).min(Self::MAX);
+    }
+
+    /// Switch between counting bars and beats, keeping the count.
+    pub fn toggle_unit(&mut self) {
+        *self = match *self {
+            CountInLength::Bars(count) => CountInLength::Beats(count),
+            CountInLength::Beats(count) => CountInLength::Bars(count),
+        };
+    }
+}
+
+impl std::fmt::Display for CountInLength {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            CountInLength::Bars(0) | CountInLength::Beats(0) => write!(f, "none"),
+            CountInLength::Bars(1) => write!(f, "1 bar"),
+            CountInLength::Bars(bars) => write!(f, "{} bars", bars),
+            CountInLength::Beats(1) => write!(f, "1 beat"),
+            CountInLength::Beats(beats) => write!(f, "{} beats", beats),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct CountInState {
     /// The beats per minute (BPM).
     pub mbpm: u32,
+    /// Whether the first loop is recorded freely to define the tempo.
+    pub free_tempo: bool,
     /// Whether to exit the application.
     pub exit: bool,
     /// Whether to enter the prepare phase.
@@ -31,14 +89,17 @@ pub struct CountInState {
     /// The audio state.
     pub audio_state: AudioState,
     // The button receiver for handling button presses.
-    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<usize>,
+    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<ButtonEvent>,
     /// The last pressed button.
     pub last_button: Option<usize>,
+    /// Whether the free recording was too short for a tempo and started over.
+    pub too_short: bool,
 }
 
 impl CountInState {
     pub async fn handle_events(&mut self) -> Result<()> {
         let event = self.event_stream.next().fuse();
+        let sleep = tokio::time::sleep(std::time::Duration::from_millis(50));
         tokio::select! {
             maybe_event = event => {
                 if let Some(event) = maybe_event {
@@ -50,10 +111,33 @@ impl CountInState {
                     }
                 }
             }
-            maybe_started = self.audio_state.started_rolling.recv() => {
-                if let Some(()) = maybe_started {
-                    self.transititon();
+            maybe_button = self.button_rx.recv() => {
+                if let Some(button) = maybe_button {
+                    self.handle_button(button);
+                }
+            }
+            _ = sleep => {
+
+            }
+        }
+        while let Some(event) = self.audio_state.try_recv() {
+            match event {
+                EngineEvent::StartedRolling => self.transititon(),
+                EngineEvent::RecordingCancelled(index) if self.free_tempo => {
+                    // Too short for a tempo, try again and say so
+                    loops::handle_event(&mut self.loops, event);
+                    self.too_short = true;
+                    self.audio_state
+                        .send(EngineCommand::StartFreeRecording(index));
+                }
+                EngineEvent::TempoChanged(mbpm) => {
+                    self.mbpm = mbpm;
+                    // Now that the tempo is known, the other loops can get their memory.
+                    // Loops that don't fit are marked, so the error itself isn't needed.
+                    let _ =
+                        loops::provide_buffers(&mut self.audio_state, &mut self.loops, mbpm, false);
                 }
+                _ => loops::handle_event(&mut self.loops, event),
             }
         }
         Ok(())
@@ -74,6 +158,7 @@ impl CountInState {
     pub fn from_prepare_state(prepare_state: crate::PrepareState) -> Self {
         CountInState {
             mbpm: prepare_state.mbpm,
+            free_tempo: prepare_state.free_tempo,
             exit: false,
             next_phase: false,
             selected: 0,
@@ -82,6 +167,7 @@ impl CountInState {
             audio_state: prepare_state.audio_state,
             button_rx: prepare_state.button_rx,
             last_button: None,
+            too_short: false,
         }
     }
 }
@@ -112,13 +198,42 @@ impl CountInState {
     }
 
     fn toggle_starting(&mut self) {
-        self.audio_state.loop_starting[self.selected]
-            .fetch_not(std::sync::atomic::Ordering::Relaxed);
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        loop_state.starting = !loop_state.starting;
+        self.audio_state.send(if loop_state.starting {
+            EngineCommand::ArmLoop(self.selected)
+        } else {
+            EngineCommand::StopLoop(self.selected)
+        });
     }
 
     fn mark_recording(&mut self) {
-        // self.audio_state.loop_starting[self.selected].store(true, std::sync::atomic::Ordering::Relaxed);
-        todo!("Wait what we do not have a recording marker???");
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        if loop_state.status.record_armed {
+            loop_state.status.record_armed = false;
+            self.audio_state
+                .send(EngineCommand::CancelRecording(self.selected));
+        } else {
+            // The loop keeps playing after the recorded pass
+            loop_state.status.record_armed = true;
+            loop_state.starting = true;
+            self.audio_state
+                .send(EngineCommand::RecordLoop(self.selected));
+        }
+    }
+
+    fn handle_button(&mut self, button: ButtonEvent) {
+        if !button.pressed {
+            return;
+        }
+        self.last_button = Some(button.id);
+        if let Some(pad) = crate::button::pad_index(button.id) {
+            self.audio_state.send(EngineCommand::TriggerPad(pad));
+        }
     }
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
@@ -126,19 +241,14 @@ impl CountInState {
             KeyCode::Char('q') => self.exit(),
             KeyCode::Up => self.select_priv(),
             KeyCode::Down => self.select_next(),
-            KeyCode::Char(' ') => self.toggle_starting(),
-            KeyCode::Char('1') => {
-                let _ = self.audio_state.pad_tx.send(0);
-            }
-            KeyCode::Char('2') => {
-                let _ = self.audio_state.pad_tx.send(1);
-            }
-            KeyCode::Char('3') => {
-                let _ = self.audio_state.pad_tx.send(2);
-            }
-            KeyCode::Char('4') => {
-                let _ = self.audio_state.pad_tx.send(3);
+            KeyCode::Char(' ') if self.free_tempo => {
+                self.audio_state.send(EngineCommand::FinishFreeRecording)
             }
+            KeyCode::Char(' ') => self.toggle_starting(),
+            KeyCode::Char('1') => self.audio_state.send(EngineCommand::TriggerPad(0)),
+            KeyCode::Char('2') => self.audio_state.send(EngineCommand::TriggerPad(1)),
+            KeyCode::Char('3') => self.audio_state.send(EngineCommand::TriggerPad(2)),
+            KeyCode::Char('4') => self.audio_state.send(EngineCommand::TriggerPad(3)),
             KeyCode::Enter => self.mark_recording(),
             _ => {}
         }
@@ -156,7 +266,11 @@ impl Widget for &CountInState {
             "(countin) ".italic(),
         ]);
         let instructions = Line::from(vec![
-            " Toggle Starting ".into(),
+            if self.free_tempo {
+                " Finish Recording ".into()
+            } else {
+                " Toggle Starting ".into()
+            },
             "<Space> ".blue().bold(),
             " Toggle Recording ".into(),
             "<Enter> ".blue().bold(),
@@ -169,7 +283,19 @@ impl Widget for &CountInState {
             .border_set(border::THICK);
 
         let bpm = self.mbpm as f64 / 1000.;
-        let mut texts = vec![Line::from(vec!["BPM: ".into(), bpm.to_string().yellow()])];
+        let mut texts = vec![Line::from(vec![
+            "BPM: ".into(),
+            if self.free_tempo {
+                "free".yellow()
+            } else {
+                bpm.to_string().yellow()
+            },
+            " Time Signature: ".into(),
+            self.audio_state.time_signature.to_string().yellow(),
+        ])];
+        if self.too_short {
+            texts.push(Line::from("Too short for a tempo, recording again".red()));
+        }
         for (index, loop_state) in self.loops.iter().enumerate() {
             let loop_text = Line::from(vec![
                 if self.selected == index {
@@ -177,8 +303,7 @@ impl Widget for &CountInState {
                 } else {
                     "".into()
                 },
-                if self.audio_state.loop_starting[index].load(std::sync::atomic::Ordering::Relaxed)
-                {
+                if loop_state.starting {
                     "🟢".green()
                 } else {
                     "🟥".red()
@@ -190,6 +315,16 @@ impl Widget for &CountInState {
                 } else {
                     "overwriting".red()
                 },
+                if loop_state.status.record_armed {
+                    " (record next)".red().bold()
+                } else {
+                    "".into()
+                },
+                if loop_state.status.out_of_memory {
+                    " (out of memory)".red()
+                } else {
+                    "".into()
+                },
             ]);
             texts.push(loop_text);
         }
@@ -200,3 +335,16 @@ impl Widget for &CountInState {
             .render(area, buf);
     }
 }
+
+#[test]
+fn test_countin_length() {
+    let waltz: TimeSignature = "3/4".parse().unwrap();
+    let mut length = CountInLength::default();
+    assert_eq!(length.beats(waltz), 6);
+    length.toggle_unit();
+    assert_eq!(length.beats(waltz), 2);
+    length.adjust(-5);
+    assert_eq!(length.to_string(), "none");
+    length.adjust(100);
+    assert_eq!(length, CountInLength::Beats(CountInLength::MAX));
+}
@@ -0,0 +1,380 @@
+use crate::filter::{Filter, PitchShift};
+
+/// The lowest and the highest fundamental detected, in Hz.
+const MIN_FREQ: f32 = 70.0;
+const MAX_FREQ: f32 = 1000.0;
+/// The input is averaged down to about this rate before detecting its pitch, to keep it cheap.
+const ANALYSIS_RATE: usize = 12000;
+/// How often the pitch is detected, in milliseconds.
+const HOP_MS: usize = 5;
+/// How aperiodic the input may be and still count as a note, lower is stricter.
+const THRESHOLD: f32 = 0.15;
+/// Quieter input is left alone, its pitch isn't reliable.
+const MIN_LEVEL: f32 = 0.01;
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// The scales the pitch can be corrected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    /// Every semitone.
+    Chromatic,
+    Major,
+    Minor,
+    MajorPentatonic,
+    MinorPentatonic,
+}
+
+impl Scale {
+    pub const ALL: [Scale; 5] = [
+        Scale::Chromatic,
+        Scale::Major,
+        Scale::Minor,
+        Scale::MajorPentatonic,
+        Scale::MinorPentatonic,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scale::Chromatic => "Chromatic",
+            Scale::Major => "Major",
+            Scale::Minor => "Minor",
+            Scale::MajorPentatonic => "Major Pentatonic",
+            Scale::MinorPentatonic => "Minor Pentatonic",
+        }
+    }
+
+    /// The next scale, wrapping around.
+    pub fn next(&self) -> Scale {
+        let index = Self::ALL
+            .iter()
+            .position(|scale| scale == self)
+            .unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    /// The notes of the scale in semitones above its key.
+    fn degrees(&self) -> &'static [i32] {
+        match self {
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+        }
+    }
+
+    /// The note of the scale in `key` nearest to `note`, both as MIDI note numbers.
+    fn nearest(&self, key: u32, note: f32) -> f32 {
+        let rounded = note.round() as i32;
+        // Every scale has a note at least every four semitones
+        (rounded - 4..=rounded + 4)
+            .filter(|candidate| {
+                let degree = (candidate - key as i32).rem_euclid(12);
+                self.degrees().contains(&degree)
+            })
+            .map(|candidate| candidate as f32)
+            .min_by(|a, b| (a - note).abs().total_cmp(&(b - note).abs()))
+            .unwrap_or(note)
+    }
+}
+
+/// The settings of the pitch correction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AutoTuneConfig {
+    /// The input channel (counting from 0) which is corrected, `None` turns the correction off.
+    pub input: Option<usize>,
+    /// The key, in semitones above C.
+    pub key: u32,
+    pub scale: Scale,
+    /// Roughly how long it takes to pull the pitch to a note in milliseconds, 0 snaps right
+    /// away.
+    pub retune_ms: u32,
+    /// Whether the loops record the corrected input instead of the dry one.
+    pub capture: bool,
+}
+
+impl Default for AutoTuneConfig {
+    fn default() -> Self {
+        AutoTuneConfig {
+            input: None,
+            key: 0,
+            scale: Scale::Chromatic,
+            retune_ms: 20,
+            capture: true,
+        }
+    }
+}
+
+impl AutoTuneConfig {
+    pub const MAX_RETUNE_MS: u32 = 500;
+
+    /// Switch to the next input of `inputs`, or off after the last one.
+    pub fn next_input(&mut self, inputs: usize) {
+        self.input = match self.input {
+            None if inputs > 0 => Some(0),
+            Some(input) if input + 1 < inputs => Some(input + 1),
+            _ => None,
+        };
+    }
+
+    /// Switch to the previous input of `inputs`, or off before the first one.
+    pub fn previous_input(&mut self, inputs: usize) {
+        self.input = match self.input {
+            None => inputs.checked_sub(1),
+            Some(input) => input.checked_sub(1),
+        };
+    }
+
+    /// Move the key by `delta` semitones, wrapping around the octave.
+    pub fn adjust_key(&mut self, delta: i32) {
+        self.key = (self.key as i32 + delta).rem_euclid(12) as u32;
+    }
+
+    /// Change the retune speed by `delta` milliseconds.
+    pub fn adjust_retune(&mut self, delta: i32) {
+        self.retune_ms = self
+            .retune_ms
+            .saturating_add_signed(delta)
+            .min(Self::MAX_RETUNE_MS);
+    }
+
+    pub fn key_name(&self) -> &'static str {
+        NOTE_NAMES[self.key as usize % 12]
+    }
+}
+
+/// Pulls the pitch of a voice to the nearest note of a scale, auto-tune style.
+///
+/// The fundamental is detected every few milliseconds with the YIN algorithm on an averaged down
+/// copy of the input. The correction then follows the distance to the nearest note of the scale,
+/// as fast as the retune speed allows, and is applied by a [`PitchShift`]. Unpitched or quiet
+/// input lets the correction relax back to none.
+#[derive(Debug, Clone)]
+pub struct AutoTune {
+    shifter: PitchShift,
+    /// The averaged down input, oldest first from `history_idx` on.
+    history: Box<[f32]>,
+    history_idx: usize,
+    /// How many input samples are averaged into one of the history.
+    decimation: usize,
+    sum: f32,
+    summed: usize,
+    /// The difference function of the detection, kept to avoid allocating.
+    difference: Box<[f32]>,
+    min_lag: usize,
+    /// How many history samples until the pitch is detected again.
+    until_detection: usize,
+    hop: usize,
+    /// The rate of the history in Hz.
+    analysis_rate: f32,
+    key: u32,
+    scale: Scale,
+    /// How much of the way to the target the correction takes at every detection.
+    retune: f32,
+    /// The current correction in semitones.
+    correction: f32,
+}
+
+impl AutoTune {
+    /// Creates a new `AutoTune` instance for a signal at `sample_rate`, which corrects to the
+    /// chromatic scale until [`Self::set_config`] is called.
+    pub fn new(sample_rate: usize) -> Self {
+        let decimation = (sample_rate / ANALYSIS_RATE).max(1);
+        let analysis_rate = sample_rate as f32 / decimation as f32;
+        let max_lag = (analysis_rate / MIN_FREQ).ceil() as usize;
+        let mut auto_tune = Self {
+            shifter: PitchShift::new(sample_rate),
+            // A window as long as the longest period, compared to every lag up to that period
+            history: vec![0.0; 2 * max_lag].into_boxed_slice(),
+            history_idx: 0,
+            decimation,
+            sum: 0.0,
+            summed: 0,
+            difference: vec![0.0; max_lag + 1].into_boxed_slice(),
+            min_lag: ((analysis_rate / MAX_FREQ).floor() as usize).max(2),
+            until_detection: 0,
+            hop: (analysis_rate as usize * HOP_MS / 1000).max(1),
+            analysis_rate,
+            key: 0,
+            scale: Scale::Chromatic,
+            retune: 1.0,
+            correction: 0.0,
+        };
+        auto_tune.set_config(&AutoTuneConfig::default());
+        auto_tune
+    }
+
+    /// Change the key, the scale and the retune speed, applied from the next detection on.
+    pub fn set_config(&mut self, config: &AutoTuneConfig) {
+        self.key = config.key;
+        self.scale = config.scale;
+        let hop_ms = self.hop as f32 * 1000.0 / self.analysis_rate;
+        self.retune = if config.retune_ms == 0 {
+            1.0
+        } else {
+            1.0 - (-hop_ms / config.retune_ms as f32).exp()
+        };
+    }
+
+    /// The current correction in semitones.
+    pub fn correction(&self) -> f32 {
+        self.correction
+    }
+
+    /// The history sample `index` samples after the oldest one.
+    fn history(&self, index: usize) -> f32 {
+        self.history[(self.history_idx + index) % self.history.len()]
+    }
+
+    /// The fundamental of the history in Hz, if it has one.
+    fn detect(&mut self) -> Option<f32> {
+        let window = self.history.len() / 2;
+        let level = (0..window).map(|i| self.history(i).abs()).sum::<f32>() / window as f32;
+        if level < MIN_LEVEL {
+            return None;
+        }
+
+        // The cumulative mean normalized difference of YIN
+        let mut total = 0.0;
+        self.difference[0] = 1.0;
+        for lag in 1..self.difference.len() {
+            let difference: f32 = (0..window)
+                .map(|i| {
+                    let delta = self.history(i) - self.history(i + lag);
+                    delta * delta
+                })
+                .sum();
+            total += difference;
+            self.difference[lag] = if total > 0.0 {
+                difference * lag as f32 / total
+            } else {
+                1.0
+            };
+        }
+
+        // The first dip below the threshold, followed down to its bottom
+        let mut lag =
+            (self.min_lag..self.difference.len()).find(|&lag| self.difference[lag] < THRESHOLD)?;
+        while lag + 1 < self.difference.len() && self.difference[lag + 1] < self.difference[lag] {
+            lag += 1;
+        }
+
+        // Find the bottom between the lags with a parabola
+        let mut period = lag as f32;
+        if lag + 1 < self.difference.len() {
+            let (before, at, after) = (
+                self.difference[lag - 1],
+                self.difference[lag],
+                self.difference[lag + 1],
+            );
+            let curvature = before - 2.0 * at + after;
+            if curvature > 0.0 {
+                period += 0.5 * (before - after) / curvature;
+            }
+        }
+        Some(self.analysis_rate / period)
+    }
+
+    /// Detect the pitch and move the correction towards the nearest note.
+    fn retune(&mut self) {
+        let target = match self.detect() {
+            Some(freq) => {
+                let note = 69.0 + 12.0 * (freq / 440.0).log2();
+                self.scale.nearest(self.key, note) - note
+            }
+            None => 0.0,
+        };
+        self.correction += (target - self.correction) * self.retune;
+        self.shifter.set_ratio((self.correction / 12.0).exp2());
+    }
+}
+
+impl Filter for AutoTune {
+    fn apply(&mut self, sample: f32) -> f32 {
+        self.sum += sample;
+        self.summed += 1;
+        if self.summed == self.decimation {
+            self.history[self.history_idx] = self.sum / self.decimation as f32;
+            self.history_idx = (self.history_idx + 1) % self.history.len();
+            self.sum = 0.0;
+            self.summed = 0;
+            if self.until_detection == 0 {
+                self.until_detection = self.hop;
+                self.retune();
+            }
+            self.until_detection -= 1;
+        }
+        self.shifter.apply(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::tone_at;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    fn tone(freq: f32, seconds: f32) -> Vec<f32> {
+        tone_at(SAMPLE_RATE, freq, seconds)
+    }
+
+    #[test]
+    fn test_scale() {
+        // A slightly flat A4 in C major
+        assert_eq!(Scale::Major.nearest(0, 68.8), 69.0);
+        // C# isn't in C major, D is nearer than C
+        assert_eq!(Scale::Major.nearest(0, 61.6), 62.0);
+        // F# is in D major
+        assert_eq!(Scale::Major.nearest(2, 66.2), 66.0);
+        assert_eq!(Scale::MinorPentatonic.nearest(9, 66.0), 67.0);
+
+        let mut config = AutoTuneConfig::default();
+        config.previous_input(2);
+        assert_eq!(config.input, Some(1));
+        config.next_input(2);
+        assert_eq!(config.input, None);
+        config.adjust_key(-1);
+        assert_eq!(config.key_name(), "B");
+    }
+
+    #[test]
+    fn test_auto_tune() {
+        let mut auto_tune = AutoTune::new(SAMPLE_RATE);
+        auto_tune.set_config(&AutoTuneConfig {
+            scale: Scale::Major,
+            retune_ms: 0,
+            ..Default::default()
+        });
+        // 430 Hz is 40 cents below A4
+        for sample in tone(430.0, 0.3) {
+            auto_tune.apply(sample);
+        }
+        let expected = -12.0 * (430.0f32 / 440.0).log2();
+        assert!(
+            (auto_tune.correction() - expected).abs() < 0.05,
+            "corrected by {}",
+            auto_tune.correction()
+        );
+
+        // Silence lets the correction go
+        for _ in 0..SAMPLE_RATE / 10 {
+            auto_tune.apply(0.0);
+        }
+        assert_eq!(auto_tune.correction(), 0.0);
+
+        // A slow retune speed takes its time
+        auto_tune.set_config(&AutoTuneConfig {
+            scale: Scale::Major,
+            retune_ms: 200,
+            ..Default::default()
+        });
+        for sample in tone(430.0, 0.1) {
+            auto_tune.apply(sample);
+        }
+        assert!(auto_tune.correction() > 0.05 && auto_tune.correction() < expected - 0.05);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::filter::Filter;
+
+/// A look-ahead peak limiter keeping the signal within `ceiling`.
+///
+/// The signal is delayed by the look-ahead, so the gain can be ramped down before a peak comes
+/// out instead of clipping it. Once the peaks are gone the gain is held for the look-ahead, then
+/// released back to unity.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    delay_line: Box<[f32]>,
+    idx: usize,
+    /// The highest absolute sample value let through.
+    pub ceiling: f32,
+    /// The gain currently applied.
+    gain: f32,
+    /// The lowest gain needed by the peaks inside the delay line.
+    target: f32,
+    /// How much the gain changes each sample while ramping down to `target`.
+    step: f32,
+    /// How many samples the gain is held before it's released.
+    hold: usize,
+    /// How much of the way back to unity the gain takes each sample while releasing.
+    release: f32,
+    /// How many samples exceeded 1.0 before limiting, see [`Self::take_overs`].
+    overs: u32,
+}
+
+impl Limiter {
+    /// Creates a new `Limiter` instance with the specified parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `lookahead` – How many samples the limiter looks ahead, which is also its latency.
+    /// * `ceiling` – The highest absolute sample value let through (0.0 to 1.0).
+    /// * `release_samples` – Roughly how many samples it takes the gain to recover.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if `lookahead` is zero or `ceiling` is outside of (0.0, 1.0].
+    pub fn new(lookahead: usize, ceiling: f32, release_samples: usize) -> Self {
+        assert!(lookahead > 0, "lookahead must be at least a sample");
+        assert!(
+            ceiling > 0.0 && ceiling <= 1.0,
+            "ceiling must be 0.0 to 1.0"
+        );
+        Self {
+            delay_line: vec![0.0; lookahead].into_boxed_slice(),
+            idx: 0,
+            ceiling,
+            gain: 1.0,
+            target: 1.0,
+            step: 0.0,
+            hold: 0,
+            release: 1.0 / release_samples.max(1) as f32,
+            overs: 0,
+        }
+    }
+
+    /// How many samples the signal is delayed.
+    pub fn latency(&self) -> usize {
+        self.delay_line.len()
+    }
+
+    /// How many samples exceeded 1.0, so would have clipped, since the last call.
+    pub fn take_overs(&mut self) -> u32 {
+        std::mem::take(&mut self.overs)
+    }
+}
+
+impl Filter for Limiter {
+    fn apply(&mut self, sample: f32) -> f32 {
+        let lookahead = self.delay_line.len();
+        let peak = sample.abs();
+        if peak > 1.0 {
+            self.overs += 1;
+        }
+        if peak > self.ceiling {
+            let needed = self.ceiling / peak;
+            // Get down to the needed gain by the time the sample comes out
+            self.step = self.step.min((needed - self.gain) / lookahead as f32);
+            self.target = self.target.min(needed);
+            self.hold = lookahead;
+        }
+
+        if self.gain > self.target {
+            self.gain = (self.gain + self.step).max(self.target);
+        } else if self.hold > 0 {
+            self.step = 0.0;
+            self.hold -= 1;
+        } else {
+            self.step = 0.0;
+            self.target = 1.0;
+            self.gain += (1.0 - self.gain) * self.release;
+        }
+
+        let delayed = std::mem::replace(&mut self.delay_line[self.idx], sample);
+        self.idx = (self.idx + 1) % lookahead;
+        // Rounding might leave the gain a hair too high
+        (delayed * self.gain).clamp(-self.ceiling, self.ceiling)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter() {
+        const LOOKAHEAD: usize = 48;
+        let mut limiter = Limiter::new(LOOKAHEAD, 0.9, 480);
+        assert_eq!(limiter.latency(), LOOKAHEAD);
+
+        // Quiet signals pass through unchanged, only delayed
+        let output: Vec<f32> = (0..100).map(|_| limiter.apply(0.5)).collect();
+        assert!(output[..LOOKAHEAD].iter().all(|&x| x == 0.0));
+        assert!(output[LOOKAHEAD..].iter().all(|&x| x == 0.5));
+
+        // A sudden peak is ramped down to instead of being cut off
+        let output: Vec<f32> = (0..1000)
+            .map(|i| limiter.apply(if i == 500 { 2.0 } else { 0.5 }))
+            .collect();
+        assert!(output.iter().all(|&x| x <= 0.9));
+        assert_eq!(output[500 + LOOKAHEAD], 0.9);
+        assert!(output[500] < 0.5 && output[500] > 0.25);
+        assert_eq!(limiter.take_overs(), 1);
+        assert_eq!(limiter.take_overs(), 0);
+
+        // And the gain recovers afterwards
+        let output: Vec<f32> = (0..10000).map(|_| limiter.apply(0.5)).collect();
+        assert!((output.last().unwrap() - 0.5).abs() < 0.001);
+    }
+}
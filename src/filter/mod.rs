@@ -1,8 +1,14 @@
+mod autotune;
 mod delay;
 mod distortion;
+mod limiter;
+mod pitch;
 mod wa;
+pub use autotune::{AutoTune, AutoTuneConfig, Scale};
 pub use delay::Delay;
 pub use distortion::Distortion;
+pub use limiter::Limiter;
+pub use pitch::{PitchShift, Transpose};
 pub use wa::Wah;
 
 pub trait Filter {
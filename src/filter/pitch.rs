@@ -0,0 +1,183 @@
+use crate::filter::Filter;
+
+/// How long the window of the pitch shifter is, in milliseconds.
+const WINDOW_MS: usize = 40;
+
+/// A pitch shift in semitones and cents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Transpose {
+    pub semitones: i32,
+    pub cents: i32,
+}
+
+impl Transpose {
+    pub const MAX_SEMITONES: i32 = 24;
+    pub const MAX_CENTS: i32 = 50;
+
+    pub fn new(semitones: i32, cents: i32) -> Self {
+        Transpose {
+            semitones: semitones.clamp(-Self::MAX_SEMITONES, Self::MAX_SEMITONES),
+            cents: cents.clamp(-Self::MAX_CENTS, Self::MAX_CENTS),
+        }
+    }
+
+    /// Change the shift by `semitones` and `cents`, each kept within its range.
+    pub fn adjust(&mut self, semitones: i32, cents: i32) {
+        *self = Self::new(self.semitones + semitones, self.cents + cents);
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// How many times higher the shifted frequencies are.
+    pub fn ratio(&self) -> f32 {
+        let cents = self.semitones * 100 + self.cents;
+        (cents as f32 / 1200.0).exp2()
+    }
+}
+
+impl std::fmt::Display for Transpose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:+} st", self.semitones)?;
+        if self.cents != 0 {
+            write!(f, " {:+} ct", self.cents)?;
+        }
+        Ok(())
+    }
+}
+
+/// A pitch shifter made of a delay line read by two taps moving at the shifted speed.
+///
+/// Each tap sweeps through the window, which plays the signal faster or slower. The taps are half
+/// a window apart and crossfaded with a triangle, so one of them is always away from the jump
+/// back. It only takes two interpolated reads per sample, cheap enough to run a few of them on a
+/// small board, at the cost of some roughness and up to a window of latency.
+#[derive(Debug, Clone)]
+pub struct PitchShift {
+    delay_line: Box<[f32]>,
+    idx: usize,
+    /// Where the first tap is in the window, from 0.0 to 1.0.
+    phase: f32,
+    /// How much the phase changes each sample.
+    step: f32,
+    /// Whether the signal passes through unchanged.
+    bypass: bool,
+}
+
+impl PitchShift {
+    /// Creates a new `PitchShift` instance which passes the signal through until
+    /// [`Self::set_transpose`] is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` – The sample rate of the signal, which decides the window length.
+    pub fn new(sample_rate: usize) -> Self {
+        let window = (sample_rate * WINDOW_MS / 1000).max(4);
+        Self {
+            // The newest sample and the interpolation need a sample beyond the window each
+            delay_line: vec![0.0; window + 2].into_boxed_slice(),
+            idx: 0,
+            phase: 0.0,
+            step: 0.0,
+            bypass: true,
+        }
+    }
+
+    /// Change the pitch shift, applied right away.
+    pub fn set_transpose(&mut self, transpose: Transpose) {
+        self.set_ratio(transpose.ratio());
+        self.bypass = transpose.is_none();
+    }
+
+    /// Shift the frequencies `ratio` times higher, applied right away.
+    ///
+    /// The signal goes through the taps even for a ratio of 1.0, so the latency doesn't jump
+    /// while the ratio keeps changing.
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.bypass = false;
+        // The delay shrinks as fast as the taps are ahead of the signal
+        self.step = (1.0 - ratio) / self.window() as f32;
+    }
+
+    fn window(&self) -> usize {
+        self.delay_line.len() - 2
+    }
+
+    /// The sample `delay` samples before the newest one, interpolated linearly.
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.delay_line.len();
+        let whole = delay as usize;
+        let fraction = delay - whole as f32;
+        let newer = self.delay_line[(self.idx + len - whole) % len];
+        let older = self.delay_line[(self.idx + 2 * len - whole - 1) % len];
+        newer + (older - newer) * fraction
+    }
+}
+
+impl Filter for PitchShift {
+    fn apply(&mut self, sample: f32) -> f32 {
+        // Keep the delay line filled, so shifting starts without a gap
+        self.idx = (self.idx + 1) % self.delay_line.len();
+        self.delay_line[self.idx] = sample;
+        if self.bypass {
+            return sample;
+        }
+
+        self.phase = (self.phase + self.step).rem_euclid(1.0);
+        let window = self.window() as f32;
+        let mut out = 0.0;
+        for phase in [self.phase, (self.phase + 0.5) % 1.0] {
+            // Silent where the tap jumps from one end of the window to the other
+            let gain = 1.0 - (2.0 * phase - 1.0).abs();
+            out += self.read(phase * window) * gain;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{SAMPLE_RATE, crossings, tone};
+
+    fn shift(transpose: Transpose) -> Vec<f32> {
+        let mut pitch_shift = PitchShift::new(SAMPLE_RATE);
+        pitch_shift.set_transpose(transpose);
+        tone(100.0, 2.0)
+            .into_iter()
+            .map(|sample| pitch_shift.apply(sample))
+            .collect()
+    }
+
+    #[test]
+    fn test_transpose() {
+        let mut transpose = Transpose::default();
+        assert!(transpose.is_none());
+        transpose.adjust(12, 0);
+        assert_eq!(transpose.ratio(), 2.0);
+        transpose.adjust(-24, -5);
+        assert_eq!(transpose.to_string(), "-12 st -5 ct");
+        transpose.adjust(-100, 100);
+        assert_eq!(
+            transpose,
+            Transpose::new(-Transpose::MAX_SEMITONES, Transpose::MAX_CENTS)
+        );
+    }
+
+    #[test]
+    fn test_pitch_shift() {
+        // Passed through unchanged without a shift
+        let mut pitch_shift = PitchShift::new(SAMPLE_RATE);
+        assert_eq!(pitch_shift.apply(0.5), 0.5);
+
+        // A 100 Hz tone crosses zero 200 times a second, past the first window
+        let settled = SAMPLE_RATE / 10;
+        let up = shift(Transpose::new(12, 0));
+        assert!(crossings(&up[settled..]).abs_diff(760) <= 40);
+        let down = shift(Transpose::new(-12, 0));
+        assert!(crossings(&down[settled..]).abs_diff(190) <= 20);
+        let fifth = shift(Transpose::new(7, 0));
+        assert!(crossings(&fifth[settled..]).abs_diff(570) <= 30);
+    }
+}
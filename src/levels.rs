@@ -0,0 +1,34 @@
+use crate::audio::{AudioState, Level};
+use crossterm::event::KeyCode;
+use ratatui::{style::Stylize, text::Line};
+
+/// How much a key press changes a level, in percent.
+const LEVEL_STEP: i32 = 10;
+
+/// Handle the level keys of the prepare and rolling phases: `v` selects the next level, `+` and
+/// `-` change the selected one.
+///
+/// Returns whether the key was a level key.
+pub fn handle_key(audio_state: &mut AudioState, selected: &mut Level, code: KeyCode) -> bool {
+    match code {
+        KeyCode::Char('v') => *selected = selected.next(),
+        KeyCode::Char('+') | KeyCode::Char('=') => audio_state.adjust_level(*selected, LEVEL_STEP),
+        KeyCode::Char('-') => audio_state.adjust_level(*selected, -LEVEL_STEP),
+        _ => return false,
+    }
+    true
+}
+
+/// A line showing the levels of both buses, with the `selected` one highlighted.
+pub fn levels_line(audio_state: &AudioState, selected: Level) -> Line<'static> {
+    let mut spans = Vec::new();
+    for level in Level::ALL {
+        let text = format!(" {}: {}% ", level.name(), audio_state.levels.get(level));
+        spans.push(if level == selected {
+            text.green().bold()
+        } else {
+            text.into()
+        });
+    }
+    Line::from(spans)
+}
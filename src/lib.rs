@@ -4,10 +4,15 @@ pub use setup::SetUpState;
 pub mod prepare;
 pub use prepare::PrepareState;
 pub mod countin;
-pub use countin::CountInState;
+pub use countin::{CountInLength, CountInState};
 pub mod rolling;
 pub use rolling::RollingState;
 pub mod blink;
 pub mod button;
 pub mod filter;
+pub mod levels;
 pub mod loops;
+pub mod meters;
+pub mod render;
+#[cfg(test)]
+mod testing;
@@ -1,3 +1,7 @@
+use crate::audio::{AudioState, EngineEvent, LOOP_COUNT, LoopMix, LoopSource, LoopSpeed};
+use crate::filter::Transpose;
+use color_eyre::Result;
+
 #[derive(Debug)]
 pub struct LoopState {
     /// The length of the loop in beats.
@@ -6,6 +10,33 @@ pub struct LoopState {
     pub starting: bool,
     /// Whether the loop should be layered on top of prievious recording.
     pub layering: bool,
+    /// What the loop records.
+    pub source: LoopSource,
+    /// How the loop is mixed.
+    pub mix: LoopMix,
+    /// How fast the loop is played.
+    pub speed: LoopSpeed,
+    /// The pitch shift of the loop's playback.
+    pub transpose: Transpose,
+    /// Whether the loop plays backwards.
+    pub reversed: bool,
+    /// What the audio engine last reported about the loop.
+    pub status: LoopStatus,
+}
+
+/// The state of a loop inside the audio engine, as reported by [`EngineEvent`]s.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LoopStatus {
+    /// Whether the loop is playing back its content.
+    pub playing: bool,
+    /// Whether the loop is capturing the input.
+    pub recording: bool,
+    /// Whether the loop holds a recording.
+    pub filled: bool,
+    /// Whether the loop will record a new pass at its next boundary.
+    pub record_armed: bool,
+    /// Whether there wasn't enough memory left for the loop, so it can't record.
+    pub out_of_memory: bool,
 }
 
 impl Default for LoopState {
@@ -14,6 +45,163 @@ impl Default for LoopState {
             beat_count: 4,
             starting: false,
             layering: false,
+            source: LoopSource::default(),
+            mix: LoopMix::default(),
+            speed: LoopSpeed::NORMAL,
+            transpose: Transpose::default(),
+            reversed: false,
+            status: LoopStatus::default(),
+        }
+    }
+}
+
+/// Update the status of the loops according to an event from the audio engine.
+pub fn handle_event(loops: &mut [LoopState], event: EngineEvent) {
+    let Some(loop_state) = event.loop_index().and_then(|index| loops.get_mut(index)) else {
+        return;
+    };
+    let status = &mut loop_state.status;
+    match event {
+        EngineEvent::LoopStartedRecording(_) => {
+            status.recording = true;
+            status.record_armed = false;
+        }
+        EngineEvent::LoopFilled(_) => {
+            status.recording = false;
+            status.filled = true;
+        }
+        EngineEvent::LoopStartedPlaying(_) => status.playing = true,
+        EngineEvent::LoopStopped(_) => status.playing = false,
+        EngineEvent::LoopCleared(_) => {
+            status.playing = false;
+            status.recording = false;
+            status.filled = false;
+        }
+        EngineEvent::RecordingCancelled(_) => status.recording = false,
+        _ => {}
+    }
+}
+
+/// How many samples of loop memory `loops` need at `mbpm` (BPM * 1000).
+///
+/// With a free tempo only the first loop is known to need memory before the tempo is found.
+pub fn samples_needed(
+    audio_state: &AudioState,
+    loops: &[LoopState],
+    mbpm: u32,
+    free_tempo: bool,
+) -> usize {
+    let buffers = &audio_state.buffers;
+    let channels = |loop_state: &LoopState| loop_state.source.channels(audio_state.channels);
+    if free_tempo {
+        return loops
+            .first()
+            .map_or(0, |first| buffers.free_recording_samples(channels(first)));
+    }
+    loops
+        .iter()
+        .map(|loop_state| buffers.samples_for(loop_state.beat_count, mbpm, channels(loop_state)))
+        .sum()
+}
+
+/// Hand the audio engine a buffer for every loop, sized for `mbpm` (BPM * 1000).
+///
+/// Loops holding a recording keep their buffer, the buffers of loops that don't exist are given
+/// back to the pool first. With a free tempo, only the first loop gets a buffer.
+/// Loops which don't fit into the memory budget are marked as out of memory.
+pub fn provide_buffers(
+    audio_state: &mut AudioState,
+    loops: &mut [LoopState],
+    mbpm: u32,
+    free_tempo: bool,
+) -> Result<()> {
+    let mut result = Ok(());
+    for index in loops.len()..LOOP_COUNT {
+        result = result.and(audio_state.buffers.release(index));
+    }
+    for (index, loop_state) in loops.iter_mut().enumerate() {
+        if loop_state.status.filled || loop_state.status.recording {
+            continue;
+        }
+        let channels = loop_state.source.channels(audio_state.channels);
+        let samples = match (free_tempo, index) {
+            (true, 0) => audio_state.buffers.free_recording_samples(channels),
+            (true, _) => 0,
+            (false, _) => audio_state
+                .buffers
+                .samples_for(loop_state.beat_count, mbpm, channels),
+        };
+        let provided = audio_state.buffers.provide(index, channels, samples);
+        loop_state.status.out_of_memory = provided.is_err();
+        result = result.and(provided);
+    }
+    result
+}
+
+/// Grow the buffers of the empty loops which are too short for `mbpm` (BPM * 1000).
+///
+/// Unlike [`provide_buffers`] this never shrinks a buffer, so following a tempo ramp beat by
+/// beat doesn't reallocate every loop. Loops the engine may start capturing into at its next
+/// boundary keep their buffer, as swapping it would throw that pass away.
+pub fn grow_buffers(audio_state: &mut AudioState, loops: &mut [LoopState], mbpm: u32) {
+    for (index, loop_state) in loops.iter_mut().enumerate() {
+        let status = &loop_state.status;
+        if status.filled || status.recording || status.record_armed || loop_state.starting {
+            continue;
         }
+        let channels = loop_state.source.channels(audio_state.channels);
+        let samples = audio_state
+            .buffers
+            .samples_for(loop_state.beat_count, mbpm, channels);
+        if samples <= audio_state.buffers.allocated(index) {
+            loop_state.status.out_of_memory = false;
+            continue;
+        }
+        loop_state.status.out_of_memory = audio_state
+            .buffers
+            .provide(index, channels, samples)
+            .is_err();
     }
 }
+
+/// Describe the mixer settings of a loop, e.g. `80% L50 muted`.
+pub fn describe_mix(mix: &LoopMix) -> String {
+    let pan = match mix.pan {
+        0 => "C".to_string(),
+        pan if pan < 0 => format!("L{}", -pan),
+        pan => format!("R{}", pan),
+    };
+    let mut text = format!("{}% {}", mix.gain, pan);
+    if mix.muted {
+        text.push_str(" muted");
+    }
+    if mix.soloed {
+        text.push_str(" solo");
+    }
+    text
+}
+
+#[test]
+fn test_grow_buffers() {
+    let (_engine, mut audio_state, _) =
+        crate::audio::engine_setup(4800, crate::audio::ChannelConfig::default());
+    let mut loops = vec![
+        LoopState::default(),
+        LoopState {
+            starting: true,
+            ..Default::default()
+        },
+    ];
+    provide_buffers(&mut audio_state, &mut loops, 120000, false).unwrap();
+    let allocated =
+        |audio_state: &AudioState| [0, 1].map(|index| audio_state.buffers.allocated(index));
+    let at_120 = allocated(&audio_state);
+
+    // A faster tempo keeps the buffers, a slower one grows those of loops that aren't armed
+    grow_buffers(&mut audio_state, &mut loops, 240000);
+    assert_eq!(allocated(&audio_state), at_120);
+    grow_buffers(&mut audio_state, &mut loops, 60000);
+    let at_60 = allocated(&audio_state);
+    assert!(at_60[0] > at_120[0]);
+    assert_eq!(at_60[1], at_120[1]);
+}
@@ -15,13 +15,19 @@ async fn main() -> Result<()> {
     color_eyre::install().inspect_err(|_| {
         eprintln!("Failed to install color_eyre");
     })?;
-    let (client, audio_state) = audio::audio_setup().inspect_err(|err| {
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|command| command == "render") {
+        return loooper::render::run(&args[2..]);
+    }
+
+    let channels = audio::ChannelConfig::from_args(&args[1..])?;
+    let (client, audio_state) = audio::audio_setup(channels).inspect_err(|err| {
         eprintln!("Failed to setup audio: {}", err);
         eprintln!("Is JACK started or pw-jack used?");
     })?;
 
     let current_millibeat = audio_state.current_millibeat.clone();
-    let pad_tx = audio_state.pad_tx.clone();
     let (blink_shutdown_tx, blink_shutdown_rx) = tokio::sync::oneshot::channel();
     let (button_shutdown_tx, button_shutdown_rx) = tokio::sync::oneshot::channel();
     let (button_tx, button_rx) = tokio::sync::mpsc::unbounded_channel();
@@ -29,7 +35,7 @@ async fn main() -> Result<()> {
     let blink_handle =
         std::thread::spawn(move || loooper::blink::blink(current_millibeat, blink_shutdown_rx));
     let button_handle =
-        std::thread::spawn(move || loooper::button::button(pad_tx, button_tx, button_shutdown_rx));
+        std::thread::spawn(move || loooper::button::button(button_tx, button_shutdown_rx));
 
     let terminal = ratatui::init();
     let state = State::default_with_audio_state(audio_state, button_rx);
@@ -55,7 +61,7 @@ async fn main() -> Result<()> {
 impl State {
     fn default_with_audio_state(
         audio_state: audio::AudioState,
-        button_rx: tokio::sync::mpsc::UnboundedReceiver<usize>,
+        button_rx: tokio::sync::mpsc::UnboundedReceiver<loooper::button::ButtonEvent>,
     ) -> Self {
         State::SetUp(SetUpState::default_with_audio_state(audio_state, button_rx))
     }
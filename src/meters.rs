@@ -0,0 +1,83 @@
+use crate::audio::{METER_COUNT, MeterSource, Meters};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    widgets::{LineGauge, Widget},
+};
+use std::time::{Duration, Instant};
+
+/// How long a peak is shown before it falls back to the current one.
+const PEAK_HOLD: Duration = Duration::from_millis(1500);
+/// The quietest level shown, in dBFS.
+const FLOOR_DB: f32 = -60.0;
+/// From which level on the meter turns yellow, in dBFS.
+const HOT_DB: f32 = -6.0;
+
+/// The peak and RMS meters of the engine, holding each peak for a while.
+#[derive(Debug)]
+pub struct MeterDisplay {
+    /// The held peak of each source and when it was reached.
+    held_peaks: [(f32, Instant); METER_COUNT],
+}
+
+impl Default for MeterDisplay {
+    fn default() -> Self {
+        MeterDisplay {
+            held_peaks: [(0.0, Instant::now()); METER_COUNT],
+        }
+    }
+}
+
+impl MeterDisplay {
+    /// Take the latest peaks from `meters`, call this before every draw.
+    pub fn update(&mut self, meters: &Meters, sources: &[MeterSource]) {
+        let now = Instant::now();
+        for &source in sources {
+            let peak = meters.take_peak(source);
+            let held = &mut self.held_peaks[source.index()];
+            if peak >= held.0 || now.duration_since(held.1) > PEAK_HOLD {
+                *held = (peak, now);
+            }
+        }
+    }
+
+    /// Draw a gauge for each of `sources`, one per line.
+    pub fn render(&self, meters: &Meters, sources: &[MeterSource], area: Rect, buf: &mut Buffer) {
+        let rows = Layout::vertical(vec![Constraint::Length(1); sources.len()]).split(area);
+        for (&source, &row) in sources.iter().zip(rows.iter()) {
+            let rms = to_db(meters.rms(source));
+            let peak = to_db(self.held_peaks[source.index()].0);
+            let color = if peak >= 0.0 {
+                Color::Red
+            } else if peak >= HOT_DB {
+                Color::Yellow
+            } else {
+                Color::Green
+            };
+            LineGauge::default()
+                .ratio(((rms - FLOOR_DB) / -FLOOR_DB) as f64)
+                .label(format!(
+                    "{:<7} {:>5.1} dB peak {:>5.1} dB",
+                    source.to_string(),
+                    rms,
+                    peak
+                ))
+                .filled_style(Style::default().fg(color))
+                .render(row, buf);
+        }
+    }
+}
+
+/// The meters worth showing with `loop_count` loops.
+pub fn sources(loop_count: usize) -> Vec<MeterSource> {
+    let mut sources = vec![MeterSource::Input];
+    sources.extend((0..loop_count).map(MeterSource::Loop));
+    sources.push(MeterSource::Master);
+    sources
+}
+
+/// Convert a linear level to dBFS, no lower than [`FLOOR_DB`].
+fn to_db(level: f32) -> f32 {
+    (20.0 * level.log10()).clamp(FLOOR_DB, 0.0)
+}
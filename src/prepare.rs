@@ -4,7 +4,7 @@ use futures::{FutureExt, StreamExt};
 use ratatui::{
     Frame,
     buffer::Buffer,
-    layout::Rect,
+    layout::{Constraint, Layout, Rect},
     style::Stylize,
     style::{Color, Styled},
     symbols::border,
@@ -12,12 +12,25 @@ use ratatui::{
     widgets::{Block, Paragraph, Widget},
 };
 
-use crate::{audio::AudioState, loops::LoopState};
+use crate::{
+    CountInLength,
+    audio::{AudioState, EngineCommand, EngineEvent, Level},
+    button::ButtonEvent,
+    levels,
+    loops::{self, LoopState},
+    meters::{self, MeterDisplay},
+};
 
 #[derive(Debug)]
 pub struct PrepareState {
     /// The beats per minute (BPM).
     pub mbpm: u32,
+    /// Whether the tempo is defined by freely recording the first loop.
+    pub free_tempo: bool,
+    /// How long the count-in lasts.
+    pub countin: CountInLength,
+    /// Whether the loops starting with the rolling phase record the last bar of the count-in.
+    pub pre_roll: bool,
     /// Whether to exit the application.
     pub exit: bool,
     /// Whether to enter the prepare phase.
@@ -29,14 +42,21 @@ pub struct PrepareState {
     /// The audio state.
     pub audio_state: AudioState,
     // The button receiver for handling button presses.
-    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<usize>,
+    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<ButtonEvent>,
     /// The last pressed button.
     pub last_button: Option<usize>,
+    /// The bus level changed by the level keys.
+    pub selected_level: Level,
+    /// How many samples the limiters caught since this phase started.
+    pub clip_count: u32,
+    /// The level meters.
+    pub meter_display: MeterDisplay,
 }
 
 impl PrepareState {
     pub async fn handle_events(&mut self) -> Result<()> {
         let event = self.event_stream.next().fuse();
+        let sleep = tokio::time::sleep(std::time::Duration::from_millis(50));
         tokio::select! {
             maybe_event = event => {
                 if let Some(event) = maybe_event {
@@ -48,7 +68,23 @@ impl PrepareState {
                     }
                 }
             }
+            maybe_button = self.button_rx.recv() => {
+                if let Some(button) = maybe_button {
+                    self.handle_button(button);
+                }
+            }
+            _ = sleep => {
+
+            }
         }
+        while let Some(event) = self.audio_state.try_recv() {
+            match event {
+                EngineEvent::Clipped(count) => self.clip_count += count,
+                _ => loops::handle_event(&mut self.loops, event),
+            }
+        }
+        self.meter_display
+            .update(&self.audio_state.meters, &meters::sources(self.loops.len()));
         Ok(())
     }
 
@@ -67,6 +103,9 @@ impl PrepareState {
     pub fn from_setup_state(setup_state: crate::SetUpState) -> Self {
         PrepareState {
             mbpm: setup_state.mbpm,
+            free_tempo: setup_state.free_tempo,
+            countin: setup_state.countin,
+            pre_roll: setup_state.pre_roll,
             exit: false,
             next_phase: false,
             loops: setup_state.loops,
@@ -74,6 +113,9 @@ impl PrepareState {
             audio_state: setup_state.audio_state,
             button_rx: setup_state.button_rx,
             last_button: None,
+            selected_level: Level::Main,
+            clip_count: 0,
+            meter_display: MeterDisplay::default(),
         }
     }
 }
@@ -88,44 +130,75 @@ impl PrepareState {
     }
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
+        if levels::handle_key(
+            &mut self.audio_state,
+            &mut self.selected_level,
+            key_event.code,
+        ) {
+            return;
+        }
         match key_event.code {
             KeyCode::Char('q') => self.exit(),
-            KeyCode::Char('1') => {
-                let _ = self.audio_state.pad_tx.send(0);
-            }
-            KeyCode::Char('2') => {
-                let _ = self.audio_state.pad_tx.send(1);
-            }
-            KeyCode::Char('3') => {
-                let _ = self.audio_state.pad_tx.send(2);
-            }
-            KeyCode::Char('4') => {
-                let _ = self.audio_state.pad_tx.send(3);
-            }
+            KeyCode::Char('1') => self.audio_state.send(EngineCommand::TriggerPad(0)),
+            KeyCode::Char('2') => self.audio_state.send(EngineCommand::TriggerPad(1)),
+            KeyCode::Char('3') => self.audio_state.send(EngineCommand::TriggerPad(2)),
+            KeyCode::Char('4') => self.audio_state.send(EngineCommand::TriggerPad(3)),
             KeyCode::Char(' ') => self.start_countin(),
             _ => {}
         }
     }
 
+    fn handle_button(&mut self, button: ButtonEvent) {
+        if !button.pressed {
+            return;
+        }
+        self.last_button = Some(button.id);
+        if let Some(pad) = crate::button::pad_index(button.id) {
+            self.audio_state.send(EngineCommand::TriggerPad(pad));
+        }
+    }
+
     fn start_countin(&mut self) {
         for (index, loop_state) in self.loops.iter().enumerate() {
-            self.audio_state.loop_length[index]
-                .store(loop_state.beat_count, std::sync::atomic::Ordering::Relaxed);
-            self.audio_state.loop_starting[index]
-                .store(loop_state.starting, std::sync::atomic::Ordering::Relaxed);
-            self.audio_state.loop_layering[index]
-                .store(loop_state.layering, std::sync::atomic::Ordering::Relaxed);
+            self.audio_state.send(EngineCommand::ConfigureLoop {
+                index,
+                length: loop_state.beat_count,
+                layering: loop_state.layering,
+            });
+            self.audio_state.send(EngineCommand::SetLoopSource {
+                index,
+                source: loop_state.source,
+            });
+            self.audio_state.send(EngineCommand::SetLoopMix {
+                index,
+                mix: loop_state.mix,
+            });
+            self.audio_state.send(EngineCommand::SetLoopSpeed {
+                index,
+                speed: loop_state.speed,
+            });
+            self.audio_state.send(EngineCommand::SetLoopTranspose {
+                index,
+                transpose: loop_state.transpose,
+            });
+            self.audio_state.send(EngineCommand::SetLoopReverse {
+                index,
+                reversed: loop_state.reversed,
+            });
+            if loop_state.starting {
+                self.audio_state.send(EngineCommand::ArmLoop(index));
+            }
         }
-        for index in self.loops.len()..8 {
-            self.audio_state.loop_starting[index]
-                .store(false, std::sync::atomic::Ordering::Relaxed);
+        if self.free_tempo {
+            // No count-in without a tempo, the first loop is recorded right away
+            self.audio_state.send(EngineCommand::StartFreeRecording(0));
+        } else {
+            let countin_length = self.countin.beats(self.audio_state.time_signature);
+            self.audio_state
+                .send(EngineCommand::SetPreRoll(self.pre_roll));
+            self.audio_state
+                .send(EngineCommand::StartCountIn(countin_length));
         }
-        self.audio_state
-            .countin_length
-            .store(8, std::sync::atomic::Ordering::Relaxed);
-        self.audio_state
-            .countin
-            .store(true, std::sync::atomic::Ordering::Relaxed);
         self.transititon();
     }
 }
@@ -141,8 +214,14 @@ impl Widget for &PrepareState {
             "(prepare) ".italic(),
         ]);
         let instructions = Line::from(vec![
-            " Start Count-in ".into(),
+            if self.free_tempo || self.countin.beats(self.audio_state.time_signature) == 0 {
+                " Start Recording ".into()
+            } else {
+                " Start Count-in ".into()
+            },
             "<Space>".blue().bold(),
+            " Level ".into(),
+            "<V> <+>/<->".blue().bold(),
             " Quit ".into(),
             "<Q> ".blue().bold(),
         ]);
@@ -153,8 +232,34 @@ impl Widget for &PrepareState {
 
         let bpm = self.mbpm as f64 / 1000.;
         let mut texts = Vec::new();
-        let counter_line = Line::from(vec!["BPM: ".into(), bpm.to_string().yellow()]);
+        let counter_line = Line::from(vec![
+            "BPM: ".into(),
+            if self.free_tempo {
+                "free".yellow()
+            } else {
+                bpm.to_string().yellow()
+            },
+            " Time Signature: ".into(),
+            self.audio_state.time_signature.to_string().yellow(),
+        ]);
         texts.push(counter_line);
+        if !self.free_tempo {
+            texts.push(Line::from(vec![
+                "Count-in: ".into(),
+                self.countin.to_string().yellow(),
+                if self.pre_roll {
+                    ", recording the last bar".into()
+                } else {
+                    "".into()
+                },
+            ]));
+        }
+        texts.push(levels::levels_line(&self.audio_state, self.selected_level));
+        if self.clip_count > 0 {
+            texts.push(Line::from(
+                format!("Limited samples: {}", self.clip_count).red(),
+            ));
+        }
         for (i, loop_state) in self.loops.iter().enumerate() {
             let loop_text = Line::from(vec![
                 if loop_state.starting {
@@ -172,10 +277,24 @@ impl Widget for &PrepareState {
             ]);
             texts.push(loop_text);
         }
+        let buffers = &self.audio_state.buffers;
+        texts.push(Line::from(vec![
+            "Record time left: ".into(),
+            format!("{:.1}s", buffers.seconds(buffers.remaining_samples())).yellow(),
+        ]));
 
+        let sources = meters::sources(self.loops.len());
+        let inner = block.inner(area);
+        block.render(area, buf);
+        let [text_area, meter_area] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(sources.len() as u16),
+        ])
+        .areas(inner);
         Paragraph::new(Text::from(texts))
             .centered()
-            .block(block)
-            .render(area, buf);
+            .render(text_area, buf);
+        self.meter_display
+            .render(&self.audio_state.meters, &sources, meter_area, buf);
     }
 }
@@ -0,0 +1,557 @@
+//! Offline rendering: run a WAV file through the [`AudioEngine`] without JACK.
+//!
+//! ```text
+//! loooper render <input.wav> <output.wav> [--bpm <bpm>] [--signature <beats>/<unit>]
+//!     [--countin <beats>] [--preroll]
+//!     [--loop <beats>[:start][:layer][:in<inputs>][:nopads][:master][:half|:double][:reverse]]...
+//!     [--script <file>]
+//!     [--tail <seconds>] [--outputs <count>] [--cue]
+//! ```
+//!
+//! A loop records every input and the pads by default. `in1+2` restricts it to the given inputs
+//! (counting from 1), `nopads` leaves out the pads and `master` resamples the master mix. `half`
+//! and `double` play the loop at half or double speed, spanning twice or half as many beats.
+//! `reverse` plays it backwards.
+//!
+//! Every channel of the input file is an input channel of the engine. The output file has as many
+//! channels as `--outputs` asks for, by default one for mono input and two otherwise. It holds the
+//! main bus, which has no metronome, unless `--cue` asks for the cue bus.
+//!
+//! `--preroll` records the last bar of the count-in into the end of the loops starting with the
+//! first beat, so a pickup isn't lost.
+//!
+//! The script contains one action per line, in the form of `<beat> <action> <index>`, where the
+//! action is one of `start`, `stop`, `toggle`, `record` or `pad`.
+//! Beats are counted like in the rolling phase: beat `1.0` is the first beat after the count-in.
+//! Actions behave just like the corresponding key presses, so loop changes only take effect at
+//! the next loop boundary.
+//!
+//! ```text
+//! # Stop the first loop and start the second one
+//! 8.5 stop 1
+//! 8.5 start 2
+//! 10 pad 3
+//! ```
+
+use crate::audio::{
+    AudioEngine, AudioState, ChannelConfig, EngineCommand, EngineEvent, InputMask, LoopSpeed,
+    TimeSignature, engine_setup,
+};
+use crate::loops::LoopState;
+use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+
+/// How many samples are processed between checking for due actions.
+const BLOCK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+    /// Start the loop at the given index at its next boundary.
+    Start(usize),
+    /// Stop the loop at the given index at its next boundary.
+    Stop(usize),
+    /// Toggle the loop at the given index, like <Space> in the rolling phase.
+    Toggle(usize),
+    /// Record a new pass into the loop at the given index, like <Enter> in the rolling phase.
+    Record(usize),
+    /// Trigger the sample pad at the given index.
+    Pad(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderAction {
+    /// When the action happens, in milli beats since the start of the rolling phase.
+    pub millibeat: u32,
+    pub kind: ActionKind,
+}
+
+#[derive(Debug)]
+pub struct RenderConfig {
+    /// The input and output channels, there must be an input channel for every channel of the
+    /// input.
+    pub channels: ChannelConfig,
+    /// The beats per minute (BPM) * 1000.
+    pub mbpm: u32,
+    /// The time signature.
+    pub time_signature: TimeSignature,
+    /// The length of the count-in in beats.
+    pub countin_length: u32,
+    /// Whether the loops starting with the first beat record the last bar of the count-in.
+    pub pre_roll: bool,
+    /// The list of loops.
+    pub loops: Vec<LoopState>,
+    /// The scripted actions, sorted by time.
+    pub actions: Vec<RenderAction>,
+    /// How many samples to keep rendering after the input ended.
+    pub tail_samples: usize,
+    /// Whether to render the cue bus, with the metronome, instead of the main bus.
+    pub cue: bool,
+}
+
+/// Parse a render script, see the module documentation for the format.
+///
+/// Loop and pad indices are 1-based in the script, just like in the user interface.
+pub fn parse_script(script: &str) -> Result<Vec<RenderAction>> {
+    let mut actions = Vec::new();
+    for (line_number, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parse_error = |reason: &str| eyre!("script line {}: {}", line_number + 1, reason);
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let [beat, action, index] = words[..] else {
+            return Err(parse_error("expected `<beat> <action> <index>`"));
+        };
+        let beat: f64 = beat.parse().map_err(|_| parse_error("invalid beat"))?;
+        if beat < 1.0 {
+            return Err(parse_error("beats start at 1"));
+        }
+        let index: usize = index.parse().map_err(|_| parse_error("invalid index"))?;
+        if index == 0 {
+            return Err(parse_error("indices start at 1"));
+        }
+        let index = index - 1;
+        let kind = match action {
+            "start" => ActionKind::Start(index),
+            "stop" => ActionKind::Stop(index),
+            "toggle" => ActionKind::Toggle(index),
+            "record" => ActionKind::Record(index),
+            "pad" => ActionKind::Pad(index),
+            _ => return Err(parse_error("unknown action")),
+        };
+        actions.push(RenderAction {
+            millibeat: (beat * 1000.0).round() as u32,
+            kind,
+        });
+    }
+    actions.sort_by_key(|action| action.millibeat);
+    Ok(actions)
+}
+
+/// Parse a loop description in the form of
+/// `<beats>[:start][:layer][:in<input>[+<input>]...][:nopads][:master][:half|:double][:reverse]`.
+pub fn parse_loop(description: &str) -> Result<LoopState> {
+    let mut parts = description.split(':');
+    let beat_count = parts
+        .next()
+        .and_then(|beats| beats.parse().ok())
+        .filter(|&beats| beats > 0)
+        .ok_or_else(|| eyre!("invalid loop length in `{}`", description))?;
+    let mut loop_state = LoopState {
+        beat_count,
+        ..Default::default()
+    };
+    for flag in parts {
+        match flag {
+            "start" => loop_state.starting = true,
+            "layer" => loop_state.layering = true,
+            "nopads" => loop_state.source.pads = false,
+            "master" => loop_state.source.master = true,
+            "half" => loop_state.speed = LoopSpeed::HALF,
+            "double" => loop_state.speed = LoopSpeed::DOUBLE,
+            "reverse" => loop_state.reversed = true,
+            _ if flag.starts_with("in") => {
+                loop_state.source.inputs = parse_inputs(&flag[2..])
+                    .ok_or_else(|| eyre!("invalid inputs `{}` in `{}`", flag, description))?;
+            }
+            _ => bail!("unknown loop flag `{}` in `{}`", flag, description),
+        }
+    }
+    Ok(loop_state)
+}
+
+/// Parse a list of input channels like `1+2`, counting from 1.
+fn parse_inputs(list: &str) -> Option<InputMask> {
+    list.split('+').try_fold(0, |mask: InputMask, input| {
+        let channel: usize = input.parse().ok()?;
+        (1..=ChannelConfig::MAX_CHANNELS)
+            .contains(&channel)
+            .then(|| mask | 1 << (channel - 1))
+    })
+}
+
+/// Run `input`, one [`Vec`] per channel, through a freshly created engine and return the
+/// rendered output channels.
+///
+/// Fails if the loops don't fit into the memory budget.
+pub fn render_samples(
+    input: &[Vec<f32>],
+    sample_rate: usize,
+    config: &RenderConfig,
+) -> Result<Vec<Vec<f32>>> {
+    let (mut engine, mut audio_state, _) = engine_setup(sample_rate, config.channels);
+    let mut starting = configure(&mut audio_state, config)?;
+
+    let input_length = input.iter().map(Vec::len).max().unwrap_or_default();
+    // Render a bit longer to make up for the latency of the limiters
+    let latency = engine.output_latency();
+    let total_length = input_length + config.tail_samples + latency;
+    let bus_channels = config.channels.outputs + config.channels.cue_channels();
+    let mut output = vec![vec![0.0; total_length]; bus_channels];
+    let mut rolling = false;
+    let mut next_action = 0;
+
+    for start in (0..total_length).step_by(BLOCK_SIZE) {
+        while let Some(event) = audio_state.try_recv() {
+            if event == EngineEvent::StartedRolling {
+                rolling = true;
+            }
+        }
+        if rolling {
+            let current_millibeat = audio_state.current_millibeat.load(Ordering::Relaxed);
+            while let Some(action) = config.actions.get(next_action) {
+                if action.millibeat > current_millibeat {
+                    break;
+                }
+                apply(&mut audio_state, &mut starting, action.kind);
+                next_action += 1;
+            }
+        }
+
+        let end = total_length.min(start + BLOCK_SIZE);
+        process_block(&mut engine, input, start..end, &mut output);
+    }
+
+    for channel in &mut output {
+        channel.drain(..latency);
+    }
+    // The cue bus follows the main bus
+    let cue_output = output.split_off(config.channels.outputs);
+    Ok(if config.cue { cue_output } else { output })
+}
+
+fn process_block(
+    engine: &mut AudioEngine,
+    input: &[Vec<f32>],
+    range: std::ops::Range<usize>,
+    output: &mut [Vec<f32>],
+) {
+    // Pad the last part of the input with silence
+    let mut blocks = [[0.0; BLOCK_SIZE]; ChannelConfig::MAX_CHANNELS];
+    for (block, channel) in blocks.iter_mut().zip(input) {
+        let end = range.end.min(channel.len());
+        let available = channel.get(range.start..end).unwrap_or_default();
+        block[..available.len()].copy_from_slice(available);
+    }
+    let inputs: Vec<&[f32]> = blocks
+        .iter()
+        .take(input.len())
+        .map(|block| &block[..range.len()])
+        .collect();
+    let mut outputs: Vec<&mut [f32]> = output
+        .iter_mut()
+        .map(|channel| &mut channel[range.clone()])
+        .collect();
+    engine.process(&inputs, &mut outputs);
+}
+
+/// Set up the loops and start the count-in, just like the set up and prepare phases do.
+///
+/// Returns whether each loop is started.
+fn configure(audio_state: &mut AudioState, config: &RenderConfig) -> Result<Vec<bool>> {
+    audio_state.send(EngineCommand::SetTempo(config.mbpm));
+    audio_state.set_time_signature(config.time_signature);
+    audio_state.send(EngineCommand::Enable);
+    for (index, loop_state) in config.loops.iter().enumerate() {
+        let channels = loop_state.source.channels(config.channels);
+        let samples = audio_state
+            .buffers
+            .samples_for(loop_state.beat_count, config.mbpm, channels);
+        audio_state.buffers.provide(index, channels, samples)?;
+        audio_state.send(EngineCommand::ConfigureLoop {
+            index,
+            length: loop_state.beat_count,
+            layering: loop_state.layering,
+        });
+        audio_state.send(EngineCommand::SetLoopSource {
+            index,
+            source: loop_state.source,
+        });
+        audio_state.send(EngineCommand::SetLoopSpeed {
+            index,
+            speed: loop_state.speed,
+        });
+        audio_state.send(EngineCommand::SetLoopReverse {
+            index,
+            reversed: loop_state.reversed,
+        });
+        if loop_state.starting {
+            audio_state.send(EngineCommand::ArmLoop(index));
+        }
+    }
+    audio_state.send(EngineCommand::SetPreRoll(config.pre_roll));
+    audio_state.send(EngineCommand::StartCountIn(config.countin_length));
+    Ok(config
+        .loops
+        .iter()
+        .map(|loop_state| loop_state.starting)
+        .collect())
+}
+
+fn apply(audio_state: &mut AudioState, starting: &mut [bool], action: ActionKind) {
+    let (index, start) = match action {
+        ActionKind::Start(index) => (index, true),
+        ActionKind::Stop(index) => (index, false),
+        ActionKind::Toggle(index) => (index, !starting.get(index).copied().unwrap_or_default()),
+        ActionKind::Record(index) => {
+            // The loop keeps playing after the recorded pass
+            if let Some(loop_starting) = starting.get_mut(index) {
+                *loop_starting = true;
+                audio_state.send(EngineCommand::RecordLoop(index));
+            }
+            return;
+        }
+        ActionKind::Pad(index) => {
+            audio_state.send(EngineCommand::TriggerPad(index));
+            return;
+        }
+    };
+    if let Some(loop_starting) = starting.get_mut(index) {
+        *loop_starting = start;
+        audio_state.send(if start {
+            EngineCommand::ArmLoop(index)
+        } else {
+            EngineCommand::StopLoop(index)
+        });
+    }
+}
+
+/// Read a WAV file into one [`Vec`] per channel.
+fn read_wav(path: &PathBuf) -> Result<(Vec<Vec<f32>>, usize)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let channels = spec.channels as usize;
+    let channel_samples = (0..channels)
+        .map(|channel| {
+            samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect()
+        })
+        .collect();
+    Ok((channel_samples, spec.sample_rate as usize))
+}
+
+/// Write one [`Vec`] per channel into a WAV file.
+fn write_wav(path: &PathBuf, channels: &[Vec<f32>], sample_rate: usize) -> Result<()> {
+    let spec = hound::WavSpec {
+        channels: channels.len() as u16,
+        sample_rate: sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    let length = channels.first().map_or(0, Vec::len);
+    for index in 0..length {
+        for channel in channels {
+            writer.write_sample(channel[index])?;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Entry point of the `render` subcommand, `args` are the arguments following `render`.
+pub fn run(args: &[String]) -> Result<()> {
+    let mut paths = Vec::new();
+    let mut bpm = 120.0;
+    let mut time_signature = TimeSignature::default();
+    let mut countin_length = None;
+    let mut pre_roll = false;
+    let mut loops = Vec::new();
+    let mut script = None;
+    let mut tail_seconds = 0.0;
+    let mut outputs = None;
+    let mut cue = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| eyre!("missing value for {}", name))
+        };
+        match arg.as_str() {
+            "--bpm" => bpm = value("--bpm")?.parse()?,
+            "--signature" => {
+                time_signature = value("--signature")?
+                    .parse()
+                    .map_err(|error: String| eyre!(error))?
+            }
+            "--countin" => countin_length = Some(value("--countin")?.parse()?),
+            "--preroll" => pre_roll = true,
+            "--loop" => loops.push(parse_loop(value("--loop")?)?),
+            "--script" => script = Some(PathBuf::from(value("--script")?)),
+            "--tail" => tail_seconds = value("--tail")?.parse()?,
+            "--outputs" => outputs = Some(value("--outputs")?.parse()?),
+            "--cue" => cue = true,
+            _ if arg.starts_with("--") => bail!("unknown option {}", arg),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let [input_path, output_path] = &paths[..] else {
+        bail!(
+            "usage: loooper render <input.wav> <output.wav> [--bpm <bpm>] \
+             [--signature <beats>/<unit>] [--countin <beats>] [--preroll] \
+             [--loop <beats>[:start][:layer][:in<inputs>][:nopads][:master][:half|:double][:reverse]]... \
+             [--script <file>] \
+             [--tail <seconds>] [--outputs <count>] [--cue]"
+        );
+    };
+    if !(30.0..=3000.0).contains(&bpm) {
+        bail!("BPM must be between 30 and 3000");
+    }
+    if loops.len() > 8 {
+        bail!("at most 8 loops are supported");
+    }
+    let mbpm = (bpm * 1000.0f64).round() as u32;
+    let actions = match script {
+        Some(path) => parse_script(&std::fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+
+    let (input, sample_rate) = read_wav(input_path)?;
+    let channels = ChannelConfig {
+        inputs: input.len(),
+        outputs: outputs.unwrap_or(input.len().min(2)),
+        ..ChannelConfig::default()
+    };
+    channels.validate()?;
+    let config = RenderConfig {
+        channels,
+        mbpm,
+        time_signature,
+        // Count in for two bars by default, like the prepare phase
+        countin_length: countin_length.unwrap_or(2 * time_signature.beats_per_bar),
+        pre_roll,
+        loops,
+        actions,
+        tail_samples: (tail_seconds * sample_rate as f64) as usize,
+        cue,
+    };
+    let output = render_samples(&input, sample_rate, &config)?;
+    write_wav(output_path, &output, sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let script = "
+            # comments and empty lines are ignored
+            8.5 stop 1
+            2 start 2 # trailing comments too
+            10 pad 3
+        ";
+        let actions = parse_script(script).unwrap();
+        assert_eq!(
+            actions,
+            vec![
+                RenderAction {
+                    millibeat: 2000,
+                    kind: ActionKind::Start(1)
+                },
+                RenderAction {
+                    millibeat: 8500,
+                    kind: ActionKind::Stop(0)
+                },
+                RenderAction {
+                    millibeat: 10000,
+                    kind: ActionKind::Pad(2)
+                },
+            ]
+        );
+
+        assert!(parse_script("1 start").is_err());
+        assert!(parse_script("1 jump 1").is_err());
+        assert!(parse_script("1 start 0").is_err());
+        assert!(parse_script("0.5 start 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_loop() {
+        let loop_state = parse_loop("8:start:layer").unwrap();
+        assert_eq!(loop_state.beat_count, 8);
+        assert!(loop_state.starting);
+        assert!(loop_state.layering);
+
+        let loop_state = parse_loop("3").unwrap();
+        assert_eq!(loop_state.beat_count, 3);
+        assert!(!loop_state.starting);
+
+        assert!(parse_loop("0").is_err());
+        assert!(parse_loop("4:loud").is_err());
+
+        let loop_state = parse_loop("4:in2+3:nopads:master").unwrap();
+        assert_eq!(loop_state.source.inputs, 0b110);
+        assert!(!loop_state.source.pads);
+        assert!(loop_state.source.master);
+        assert!(loop_state.speed.is_normal());
+        assert_eq!(parse_loop("4:half").unwrap().speed, LoopSpeed::HALF);
+        assert!(!loop_state.reversed);
+        assert!(parse_loop("4:double:reverse").unwrap().reversed);
+        assert!(parse_loop("4:in9").is_err());
+        assert!(parse_loop("4:in").is_err());
+    }
+
+    #[test]
+    fn test_render_plays_back_loop() {
+        const SAMPLE_RATE: usize = 4800;
+        // 120 BPM at 4800 Hz
+        const SAMPLES_PER_BEAT: usize = 2400;
+        let render = |loops: &str, script: &str| {
+            let config = RenderConfig {
+                channels: ChannelConfig::default(),
+                mbpm: 120000,
+                time_signature: TimeSignature::default(),
+                countin_length: 0,
+                pre_roll: false,
+                loops: loops
+                    .split_whitespace()
+                    .map(|l| parse_loop(l).unwrap())
+                    .collect(),
+                actions: parse_script(script).unwrap(),
+                tail_samples: 2 * SAMPLES_PER_BEAT,
+                cue: false,
+            };
+            // A single impulse in the recorded beat
+            let mut input = vec![0.0; 2 * SAMPLES_PER_BEAT];
+            input[100] = 0.5;
+            let mut output = render_samples(&[input], SAMPLE_RATE, &config).unwrap();
+            output.remove(0)
+        };
+
+        let without_loop = render("", "");
+        let stopped = render("1:start", "2.5 stop 1");
+        assert_eq!(without_loop.len(), 4 * SAMPLES_PER_BEAT);
+        assert_eq!(stopped.len(), 4 * SAMPLES_PER_BEAT);
+
+        // The loop repeats the impulse in the second beat...
+        assert_ne!(
+            stopped[SAMPLES_PER_BEAT + 100],
+            without_loop[SAMPLES_PER_BEAT + 100]
+        );
+        // ...and is stopped by the script before the third one
+        assert_eq!(
+            stopped[2 * SAMPLES_PER_BEAT..],
+            without_loop[2 * SAMPLES_PER_BEAT..]
+        );
+    }
+}
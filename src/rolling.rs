@@ -4,7 +4,7 @@ use futures::{FutureExt, StreamExt};
 use ratatui::{
     Frame,
     buffer::Buffer,
-    layout::Rect,
+    layout::{Constraint, Layout, Rect},
     style::Stylize,
     style::{Color, Styled},
     symbols::border,
@@ -12,12 +12,38 @@ use ratatui::{
     widgets::{Block, Paragraph, Widget},
 };
 
-use crate::{audio::AudioState, loops::LoopState};
+use crate::{
+    audio::{
+        AudioState, ChannelConfig, EngineCommand, EngineEvent, Level, LoopMix, LoopSource,
+        LoopSpeed,
+    },
+    button::ButtonEvent,
+    levels,
+    loops::{self, LoopState},
+    meters::{self, MeterDisplay},
+};
+
+/// How much a key press changes the gain of a loop, in percent.
+const GAIN_STEP: i32 = 10;
+/// How much a key press changes the balance of a loop.
+const PAN_STEP: i32 = 10;
+/// How much a key press changes a pitch shift in cents.
+const CENTS_STEP: i32 = 5;
+/// How much a key press changes the varispeed of a loop, in percent.
+const SPEED_STEP: i32 = 1;
+/// How much a key press nudges the tempo, in BPM * 1000.
+const TEMPO_NUDGE: i64 = 1000;
+/// How much a key press changes the tempo to ramp to, in BPM * 1000.
+const RAMP_STEP: i64 = 5000;
+/// How many bars a tempo ramp lasts.
+const RAMP_BARS: u32 = 4;
 
 #[derive(Debug)]
 pub struct RollingState {
     /// The beats per minute (BPM).
     pub mbpm: u32,
+    /// The tempo to ramp to, in BPM * 1000.
+    pub ramp_target: u32,
     /// Whether to exit the application.
     pub exit: bool,
     /// Whether to enter the prepare phase.
@@ -31,9 +57,19 @@ pub struct RollingState {
     /// The audio state.
     pub audio_state: AudioState,
     // The button receiver for handling button presses.
-    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<usize>,
+    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<ButtonEvent>,
     /// The last pressed button.
     pub last_button: Option<usize>,
+    /// The bus level changed by the level keys.
+    pub selected_level: Level,
+    /// Whether the pitch keys shift the monitored input instead of the selected loop.
+    pub transpose_monitor: bool,
+    /// How many samples the limiters caught since this phase started.
+    pub clip_count: u32,
+    /// The level meters.
+    pub meter_display: MeterDisplay,
+    /// How many xruns happened since rolling started.
+    pub xrun_count: usize,
 }
 
 impl RollingState {
@@ -51,10 +87,25 @@ impl RollingState {
                     }
                 }
             },
+            maybe_button = self.button_rx.recv() => {
+                if let Some(button) = maybe_button {
+                    self.handle_button(button);
+                }
+            }
             _ = sleep => {
 
             }
         }
+        while let Some(event) = self.audio_state.try_recv() {
+            match event {
+                EngineEvent::Xrun => self.xrun_count += 1,
+                EngineEvent::Clipped(count) => self.clip_count += count,
+                EngineEvent::TempoChanged(mbpm) => self.tempo_changed(mbpm),
+                _ => loops::handle_event(&mut self.loops, event),
+            }
+        }
+        self.meter_display
+            .update(&self.audio_state.meters, &meters::sources(self.loops.len()));
         Ok(())
     }
 
@@ -73,6 +124,7 @@ impl RollingState {
     pub fn from_countin_state(countin_state: crate::CountInState) -> Self {
         RollingState {
             mbpm: countin_state.mbpm,
+            ramp_target: countin_state.mbpm,
             exit: false,
             next_phase: false,
             selected: countin_state.selected,
@@ -81,6 +133,11 @@ impl RollingState {
             audio_state: countin_state.audio_state,
             button_rx: countin_state.button_rx,
             last_button: None,
+            selected_level: Level::Main,
+            transpose_monitor: false,
+            clip_count: 0,
+            meter_display: MeterDisplay::default(),
+            xrun_count: 0,
         }
     }
 }
@@ -111,35 +168,193 @@ impl RollingState {
     }
 
     fn toggle_starting(&mut self) {
-        self.audio_state.loop_starting[self.selected]
-            .fetch_not(std::sync::atomic::Ordering::Relaxed);
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        loop_state.starting = !loop_state.starting;
+        self.audio_state.send(if loop_state.starting {
+            EngineCommand::ArmLoop(self.selected)
+        } else {
+            EngineCommand::StopLoop(self.selected)
+        });
     }
 
     fn mark_recording(&mut self) {
-        // self.audio_state.loop_starting[self.selected].store(true, std::sync::atomic::Ordering::Relaxed);
-        todo!("Wait what we do not have a recording marker???");
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        if loop_state.status.record_armed {
+            loop_state.status.record_armed = false;
+            self.audio_state
+                .send(EngineCommand::CancelRecording(self.selected));
+        } else {
+            // The loop keeps playing after the recorded pass
+            loop_state.status.record_armed = true;
+            loop_state.starting = true;
+            self.audio_state
+                .send(EngineCommand::RecordLoop(self.selected));
+        }
+    }
+
+    /// Keep the buffers of the empty loops long enough for their beats at the new tempo.
+    fn tempo_changed(&mut self, mbpm: u32) {
+        self.mbpm = mbpm;
+        // A loop which doesn't fit anymore is marked as out of memory
+        loops::grow_buffers(&mut self.audio_state, &mut self.loops, mbpm);
+    }
+
+    /// Change the tempo at the next beat, the recorded loops are stretched to follow it.
+    fn nudge_tempo(&mut self, delta: i64) {
+        let mbpm = (self.mbpm as i64 + delta).clamp(30000, 3000000) as u32;
+        self.audio_state.send(EngineCommand::SetTempo(mbpm));
+        self.tempo_changed(mbpm);
+    }
+
+    fn adjust_ramp_target(&mut self, delta: i64) {
+        self.ramp_target = (self.ramp_target as i64 + delta).clamp(30000, 3000000) as u32;
+    }
+
+    /// Change the tempo to the ramp target gradually, over a few bars.
+    fn start_ramp(&mut self) {
+        self.audio_state.send(EngineCommand::RampTempo {
+            mbpm: self.ramp_target,
+            beats: RAMP_BARS * self.audio_state.time_signature.beats_per_bar,
+        });
+    }
+
+    fn handle_button(&mut self, button: ButtonEvent) {
+        if let Some(index) = crate::button::loop_index(button.id) {
+            // The loop is muted while the button is held
+            self.change_mix(index, |mix| mix.muted = button.pressed);
+        }
+        if !button.pressed {
+            return;
+        }
+        self.last_button = Some(button.id);
+        if let Some(pad) = crate::button::pad_index(button.id) {
+            self.audio_state.send(EngineCommand::TriggerPad(pad));
+        }
+        if crate::button::is_reverse(button.id) {
+            self.toggle_reverse();
+        }
+    }
+
+    fn clear_loop(&mut self) {
+        self.audio_state
+            .send(EngineCommand::ClearLoop(self.selected));
+    }
+
+    /// Change what the selected loop records, right away.
+    fn change_source(&mut self, change: impl FnOnce(&mut LoopSource, ChannelConfig)) {
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        change(&mut loop_state.source, self.audio_state.channels);
+        self.audio_state.send(EngineCommand::SetLoopSource {
+            index: self.selected,
+            source: loop_state.source,
+        });
+        // An empty loop gets a buffer with the channels of its new source
+        let _ = loops::provide_buffers(&mut self.audio_state, &mut self.loops, self.mbpm, false);
+    }
+
+    /// Change the mixer settings of the loop at `index`, right away.
+    fn change_mix(&mut self, index: usize, change: impl FnOnce(&mut LoopMix)) {
+        let Some(loop_state) = self.loops.get_mut(index) else {
+            return;
+        };
+        change(&mut loop_state.mix);
+        self.audio_state.send(EngineCommand::SetLoopMix {
+            index,
+            mix: loop_state.mix,
+        });
+    }
+
+    /// Change how fast the selected loop plays, right away.
+    fn change_speed(&mut self, change: impl FnOnce(&mut LoopSpeed)) {
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        change(&mut loop_state.speed);
+        self.audio_state.send(EngineCommand::SetLoopSpeed {
+            index: self.selected,
+            speed: loop_state.speed,
+        });
+    }
+
+    /// Play the selected loop backwards or forwards again, right away.
+    fn toggle_reverse(&mut self) {
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        loop_state.reversed = !loop_state.reversed;
+        self.audio_state.send(EngineCommand::SetLoopReverse {
+            index: self.selected,
+            reversed: loop_state.reversed,
+        });
+    }
+
+    /// Shift the pitch of the monitored input or the selected loop, right away.
+    fn transpose(&mut self, semitones: i32, cents: i32) {
+        if self.transpose_monitor {
+            let mut transpose = self.audio_state.monitor_transpose;
+            transpose.adjust(semitones, cents);
+            self.audio_state.set_monitor_transpose(transpose);
+            return;
+        }
+        let Some(loop_state) = self.loops.get_mut(self.selected) else {
+            return;
+        };
+        loop_state.transpose.adjust(semitones, cents);
+        self.audio_state.send(EngineCommand::SetLoopTranspose {
+            index: self.selected,
+            transpose: loop_state.transpose,
+        });
     }
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
+        if levels::handle_key(
+            &mut self.audio_state,
+            &mut self.selected_level,
+            key_event.code,
+        ) {
+            return;
+        }
         match key_event.code {
-            KeyCode::Char('1') => {
-                let _ = self.audio_state.pad_tx.send(0);
-            }
-            KeyCode::Char('2') => {
-                let _ = self.audio_state.pad_tx.send(1);
-            }
-            KeyCode::Char('3') => {
-                let _ = self.audio_state.pad_tx.send(2);
-            }
-            KeyCode::Char('4') => {
-                let _ = self.audio_state.pad_tx.send(3);
-            }
+            KeyCode::Char('1') => self.audio_state.send(EngineCommand::TriggerPad(0)),
+            KeyCode::Char('2') => self.audio_state.send(EngineCommand::TriggerPad(1)),
+            KeyCode::Char('3') => self.audio_state.send(EngineCommand::TriggerPad(2)),
+            KeyCode::Char('4') => self.audio_state.send(EngineCommand::TriggerPad(3)),
             KeyCode::Char('q') => self.exit(),
             KeyCode::Esc => self.transititon(),
             KeyCode::Up => self.select_priv(),
             KeyCode::Down => self.select_next(),
             KeyCode::Char(' ') => self.toggle_starting(),
             KeyCode::Enter => self.mark_recording(),
+            KeyCode::Char('c') => self.clear_loop(),
+            KeyCode::Char('i') => self.change_source(LoopSource::cycle_inputs),
+            KeyCode::Char('d') => self.change_source(|source, _| source.pads = !source.pads),
+            KeyCode::Char('m') => self.change_source(|source, _| source.master = !source.master),
+            KeyCode::Left => self.change_mix(self.selected, |mix| mix.adjust_pan(-PAN_STEP)),
+            KeyCode::Right => self.change_mix(self.selected, |mix| mix.adjust_pan(PAN_STEP)),
+            KeyCode::Char(',') => self.change_mix(self.selected, |mix| mix.adjust_gain(-GAIN_STEP)),
+            KeyCode::Char('.') => self.change_mix(self.selected, |mix| mix.adjust_gain(GAIN_STEP)),
+            KeyCode::Char('u') => self.change_mix(self.selected, |mix| mix.muted = !mix.muted),
+            KeyCode::Char('s') => self.change_mix(self.selected, |mix| mix.soloed = !mix.soloed),
+            KeyCode::Char('w') => self.change_speed(|speed| *speed = speed.next_preset()),
+            KeyCode::Char('<') => self.change_speed(|speed| speed.adjust(-SPEED_STEP)),
+            KeyCode::Char('>') => self.change_speed(|speed| speed.adjust(SPEED_STEP)),
+            KeyCode::Char('r') => self.toggle_reverse(),
+            KeyCode::Char('p') => self.transpose_monitor = !self.transpose_monitor,
+            KeyCode::Char('k') => self.transpose(-1, 0),
+            KeyCode::Char('l') => self.transpose(1, 0),
+            KeyCode::Char('K') => self.transpose(0, -CENTS_STEP),
+            KeyCode::Char('L') => self.transpose(0, CENTS_STEP),
+            KeyCode::Char('[') => self.nudge_tempo(-TEMPO_NUDGE),
+            KeyCode::Char(']') => self.nudge_tempo(TEMPO_NUDGE),
+            KeyCode::Char('{') => self.adjust_ramp_target(-RAMP_STEP),
+            KeyCode::Char('}') => self.adjust_ramp_target(RAMP_STEP),
+            KeyCode::Char('t') => self.start_ramp(),
             _ => {}
         }
     }
@@ -160,8 +375,28 @@ impl Widget for &RollingState {
             "<Space> ".blue().bold(),
             " Toggle Recording ".into(),
             "<Enter> ".blue().bold(),
+            " Clear Loop ".into(),
+            "<C>".blue().bold(),
+            " Input/Pads/Master ".into(),
+            "<I>/<D>/<M>".blue().bold(),
+            " Gain ".into(),
+            "<,>/<.>".blue().bold(),
+            " Pan ".into(),
+            "<←>/<→>".blue().bold(),
+            " Mute/Solo ".into(),
+            "<U>/<S>".blue().bold(),
+            " Speed ".into(),
+            "<W> <<>/<>>".blue().bold(),
+            " Pitch Loop/Monitor ".into(),
+            "<P> <K>/<L>".blue().bold(),
+            " Tempo ".into(),
+            "<[>/<]>".blue().bold(),
+            " Ramp ".into(),
+            "<{>/<}> <T>".blue().bold(),
             " Reset Loooper ".into(),
             "<Esc>".blue().bold(),
+            " Level ".into(),
+            "<V> <+>/<->".blue().bold(),
             " Quit ".into(),
             "<Q> ".blue().bold(),
         ]);
@@ -175,16 +410,49 @@ impl Widget for &RollingState {
             .audio_state
             .current_millibeat
             .load(std::sync::atomic::Ordering::Relaxed);
+        let time_signature = self.audio_state.time_signature;
+        let (bar, beat) = time_signature.bar_and_beat(current_millibeat / 1000);
         let mut texts = vec![Line::from(vec![
             "BPM: ".into(),
             bpm.to_string().yellow(),
-            format!(
-                " Beat: {}.{}",
-                current_millibeat / 1000,
-                current_millibeat % 1000
-            )
-            .into(),
+            " Time Signature: ".into(),
+            time_signature.to_string().yellow(),
+            format!(" Bar: {} Beat: {}.{}", bar, beat, current_millibeat % 1000).into(),
+            " Ramp to: ".into(),
+            (self.ramp_target as f64 / 1000.).to_string().yellow(),
         ])];
+        texts.push(levels::levels_line(&self.audio_state, self.selected_level));
+        texts.push(Line::from(vec![
+            "Monitor pitch: ".into(),
+            if self.transpose_monitor {
+                self.audio_state
+                    .monitor_transpose
+                    .to_string()
+                    .green()
+                    .bold()
+            } else {
+                self.audio_state.monitor_transpose.to_string().yellow()
+            },
+        ]));
+        if self.xrun_count > 0 {
+            texts.push(Line::from(format!("Xruns: {}", self.xrun_count).red()));
+        }
+        if self.clip_count > 0 {
+            texts.push(Line::from(
+                format!("Limited samples: {}", self.clip_count).red(),
+            ));
+        }
+        let dropped_messages = self.audio_state.dropped_messages();
+        if dropped_messages > 0 {
+            texts.push(Line::from(
+                format!("Dropped audio messages: {}", dropped_messages).red(),
+            ));
+        }
+        let buffers = &self.audio_state.buffers;
+        texts.push(Line::from(vec![
+            "Record time left: ".into(),
+            format!("{:.1}s", buffers.seconds(buffers.remaining_samples())).yellow(),
+        ]));
         for (index, loop_state) in self.loops.iter().enumerate() {
             let loop_text = Line::from(vec![
                 if self.selected == index {
@@ -192,17 +460,14 @@ impl Widget for &RollingState {
                 } else {
                     "".into()
                 },
-                if self.audio_state.loop_starting[index].load(std::sync::atomic::Ordering::Relaxed)
-                {
+                if loop_state.starting {
                     "🟢".green()
                 } else {
                     "🟥".red()
                 },
-                if self.audio_state.loop_playing[index].load(std::sync::atomic::Ordering::Relaxed) {
+                if loop_state.status.playing {
                     "▶️".green()
-                } else if self.audio_state.loop_recording[index]
-                    .load(std::sync::atomic::Ordering::Relaxed)
-                {
+                } else if loop_state.status.recording {
                     "⏺️".black()
                 } else {
                     "⏹️".black()
@@ -214,13 +479,58 @@ impl Widget for &RollingState {
                 } else {
                     "overwriting".red()
                 },
+                ", from ".into(),
+                loop_state
+                    .source
+                    .describe(self.audio_state.channels)
+                    .yellow(),
+                ", ".into(),
+                if loop_state.mix.muted {
+                    loops::describe_mix(&loop_state.mix).red()
+                } else {
+                    loops::describe_mix(&loop_state.mix).yellow()
+                },
+                if loop_state.transpose.is_none() {
+                    "".into()
+                } else {
+                    format!(", {}", loop_state.transpose).yellow()
+                },
+                if loop_state.speed.is_normal() {
+                    "".into()
+                } else {
+                    format!(", {}", loop_state.speed).yellow()
+                },
+                if loop_state.reversed {
+                    ", reversed".yellow()
+                } else {
+                    "".into()
+                },
+                if loop_state.status.record_armed {
+                    " (record next)".red().bold()
+                } else {
+                    "".into()
+                },
+                if loop_state.status.out_of_memory {
+                    " (out of memory)".red()
+                } else {
+                    "".into()
+                },
             ]);
             texts.push(loop_text);
         }
 
+        let sources = meters::sources(self.loops.len());
+        let inner = block.inner(area);
+        block.render(area, buf);
+        let [text_area, meter_area] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(sources.len() as u16),
+        ])
+        .areas(inner);
         Paragraph::new(Text::from(texts))
             .centered()
-            .block(block)
-            .render(area, buf);
+            .render(text_area, buf);
+        self.meter_display
+            .render(&self.audio_state.meters, &sources, meter_area, buf);
     }
 }
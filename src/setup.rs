@@ -1,5 +1,10 @@
-use crate::audio::AudioState;
-use crate::loops::LoopState;
+use crate::CountInLength;
+use crate::audio::{
+    AudioState, ClickPhase, ClickSound, EngineCommand, MetronomeConfig, TimeSignature,
+};
+use crate::button::ButtonEvent;
+use crate::filter::AutoTuneConfig;
+use crate::loops::{self, LoopState};
 use color_eyre::Result;
 use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind};
 use futures::{FutureExt, StreamExt};
@@ -10,9 +15,10 @@ use ratatui::{
     style::Stylize,
     style::{Color, Styled},
     symbols::border,
-    text::{Line, Text},
+    text::{Line, Span, Text},
     widgets::{Block, Paragraph, Widget},
 };
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct SetUpState {
@@ -20,11 +26,30 @@ pub struct SetUpState {
     pub mbpm: u32,
     /// The precision of the BPM adjustment.
     pub precision: u32,
+    /// Whether the tempo is defined by freely recording the first loop instead.
+    pub free_tempo: bool,
+    /// The time signature.
+    pub time_signature: TimeSignature,
+    /// How long the count-in lasts.
+    pub countin: CountInLength,
+    /// Whether the loops starting with the rolling phase record the last bar of the count-in.
+    pub pre_roll: bool,
+    /// The metronome settings.
+    pub metronome: MetronomeConfig,
+    /// The phase whose click volume is edited.
+    click_phase: ClickPhase,
+    /// The beat of the bar (counting from 0) whose accent is edited.
+    accent_beat: u32,
+    /// The path of the click sample while it's typed in.
+    click_file_input: Option<String>,
+    /// The pitch correction settings.
+    pub auto_tune: AutoTuneConfig,
     /// Whether to exit the application.
     pub exit: bool,
     /// Whether to enter the prepare phase.
     pub next_phase: bool,
-    /// The selected loop index.
+    /// The selected row, the BPM, the time signature, the count-in, the metronome and the
+    /// auto-tune come before the loops.
     pub selected: usize,
     /// The list of loops.
     pub loops: Vec<LoopState>,
@@ -37,7 +62,7 @@ pub struct SetUpState {
     /// The last error message.
     last_error: String,
     // The button receiver for handling button presses.
-    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<usize>,
+    pub button_rx: tokio::sync::mpsc::UnboundedReceiver<ButtonEvent>,
     /// The last pressed button.
     pub last_button: Option<usize>,
     button_press_count: usize,
@@ -46,18 +71,27 @@ pub struct SetUpState {
 impl SetUpState {
     pub fn default_with_audio_state(
         audio_state: crate::audio::AudioState,
-        button_rx: tokio::sync::mpsc::UnboundedReceiver<usize>,
+        button_rx: tokio::sync::mpsc::UnboundedReceiver<ButtonEvent>,
     ) -> Self {
         SetUpState {
             mbpm: 120000,
             precision: 10000,
+            free_tempo: false,
+            time_signature: TimeSignature::default(),
+            countin: CountInLength::default(),
+            pre_roll: false,
+            metronome: audio_state.metronome,
+            click_phase: ClickPhase::Idle,
+            accent_beat: 0,
+            click_file_input: None,
+            auto_tune: audio_state.auto_tune,
             exit: false,
             next_phase: false,
             selected: 0,
             loops: vec![LoopState {
                 beat_count: 4,
                 starting: true,
-                layering: false,
+                ..Default::default()
             }],
             event_stream: EventStream::new(),
             audio_state,
@@ -89,12 +123,17 @@ impl SetUpState {
                 }
             }
             maybe_button = self.button_rx.recv() => {
-                if let Some(button) = maybe_button {
-                    self.last_button = Some(button);
+                if let Some(button) = maybe_button.filter(|button| button.pressed) {
+                    self.last_button = Some(button.id);
                     self.button_press_count += 1;
+                    if let Some(pad) = crate::button::pad_index(button.id) {
+                        self.audio_state.send(EngineCommand::TriggerPad(pad));
+                    }
                 }
             }
         }
+        // The engine is disabled during set up, there's nothing to react to
+        while self.audio_state.try_recv().is_some() {}
         Ok(())
     }
 
@@ -110,21 +149,27 @@ impl SetUpState {
         frame.render_widget(self, frame.area());
     }
 
-    pub fn from_rolling_state(rolling_state: crate::RollingState) -> Self {
-        rolling_state
-            .audio_state
-            .enabled
-            .store(false, std::sync::atomic::Ordering::Relaxed);
+    pub fn from_rolling_state(mut rolling_state: crate::RollingState) -> Self {
+        rolling_state.audio_state.send(EngineCommand::Disable);
         SetUpState {
             mbpm: rolling_state.mbpm,
             precision: 10000,
+            free_tempo: false,
+            time_signature: rolling_state.audio_state.time_signature,
+            countin: CountInLength::default(),
+            pre_roll: false,
+            metronome: rolling_state.audio_state.metronome,
+            click_phase: ClickPhase::Idle,
+            accent_beat: 0,
+            click_file_input: None,
+            auto_tune: rolling_state.audio_state.auto_tune,
             exit: false,
             next_phase: false,
             selected: 0,
             loops: vec![LoopState {
                 beat_count: 4,
                 starting: true,
-                layering: false,
+                ..Default::default()
             }],
             event_stream: rolling_state.event_stream,
             audio_state: rolling_state.audio_state,
@@ -137,50 +182,167 @@ impl SetUpState {
     }
 }
 
+/// The rows of the setup screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Row {
+    Tempo,
+    TimeSignature,
+    CountIn,
+    PreRoll,
+    ClickSound,
+    ClickFile,
+    ClickVolume,
+    Accents,
+    Subdivision,
+    ClickMute,
+    AutoTune,
+    TuneKey,
+    Retune,
+    Loop(usize),
+}
+
+/// The rows before the loops.
+const SETTING_ROWS: [Row; 13] = [
+    Row::Tempo,
+    Row::TimeSignature,
+    Row::CountIn,
+    Row::PreRoll,
+    Row::ClickSound,
+    Row::ClickFile,
+    Row::ClickVolume,
+    Row::Accents,
+    Row::Subdivision,
+    Row::ClickMute,
+    Row::AutoTune,
+    Row::TuneKey,
+    Row::Retune,
+];
+
+/// How many rows come before the loops.
+const FIRST_LOOP_ROW: usize = SETTING_ROWS.len();
+
+/// How much a key press changes a click volume, in percent.
+const CLICK_VOLUME_STEP: i32 = 5;
+/// How much a key press changes the retune speed, in milliseconds.
+const RETUNE_STEP: i32 = 10;
+
 impl SetUpState {
+    fn selected_row(&self) -> Row {
+        SETTING_ROWS
+            .get(self.selected)
+            .copied()
+            .unwrap_or(Row::Loop(self.selected - FIRST_LOOP_ROW))
+    }
+
+    fn selected_loop(&mut self) -> Option<&mut LoopState> {
+        match self.selected_row() {
+            Row::Loop(index) => self.loops.get_mut(index),
+            _ => None,
+        }
+    }
+
     fn exit(&mut self) {
         self.exit = true;
     }
 
     fn transititon(&mut self) {
-        self.audio_state
-            .enabled
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        self.audio_state
-            .mbpm
-            .store(self.mbpm, std::sync::atomic::Ordering::Relaxed);
+        if let Err(error) = loops::provide_buffers(
+            &mut self.audio_state,
+            &mut self.loops,
+            self.mbpm,
+            self.free_tempo,
+        ) {
+            self.last_error = error.to_string();
+            self.error_count += 1;
+            return;
+        }
+        self.audio_state.send(EngineCommand::SetTempo(self.mbpm));
+        self.audio_state.set_time_signature(self.time_signature);
+        self.audio_state.set_metronome(self.metronome);
+        self.audio_state.set_auto_tune(self.auto_tune);
+        self.audio_state.send(EngineCommand::Enable);
         self.next_phase = true;
     }
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
+        if self.click_file_input.is_some() {
+            self.edit_click_file(key_event.code);
+            return;
+        }
         match key_event.code {
             KeyCode::Char('q') => self.exit(),
             KeyCode::Left => self.decrement(),
             KeyCode::Right => self.increment(),
             KeyCode::Up => self.select_priv(),
             KeyCode::Down => self.select_next(),
-            KeyCode::Tab => {
-                if self.selected == 0 {
-                    self.change_precision()
-                } else {
-                    self.toggle_autostart()
+            KeyCode::Tab => match self.selected_row() {
+                Row::Tempo => self.change_precision(),
+                Row::TimeSignature => self.time_signature.cycle_beat_unit(),
+                Row::CountIn => self.countin.toggle_unit(),
+                Row::PreRoll => self.pre_roll = !self.pre_roll,
+                Row::ClickVolume => self.click_phase = self.click_phase.next(),
+                Row::Accents => self.metronome.toggle_accent(self.accent_beat),
+                Row::ClickSound | Row::Subdivision | Row::ClickMute => self.cycle_metronome(),
+                Row::ClickFile => {
+                    self.click_file_input = Some(
+                        self.audio_state
+                            .click_file
+                            .as_ref()
+                            .map(|path| path.display().to_string())
+                            .unwrap_or_default(),
+                    )
                 }
-            }
+                Row::AutoTune => self.auto_tune.capture = !self.auto_tune.capture,
+                Row::TuneKey => self.auto_tune.scale = self.auto_tune.scale.next(),
+                Row::Retune => {
+                    // Between snapping right away and the default glide
+                    self.auto_tune.retune_ms = match self.auto_tune.retune_ms {
+                        0 => AutoTuneConfig::default().retune_ms,
+                        _ => 0,
+                    }
+                }
+                Row::Loop(_) => self.toggle_autostart(),
+            },
             KeyCode::Char(' ') => self.transititon(),
             KeyCode::Char('p') => panic!("Manual panic!"),
             KeyCode::Char('a') => self.add_loop(),
             KeyCode::Char('l') => self.toggle_layering(),
-            KeyCode::Char('1') => {
-                let _ = self.audio_state.pad_tx.send(0);
-            }
-            KeyCode::Char('2') => {
-                let _ = self.audio_state.pad_tx.send(1);
-            }
-            KeyCode::Char('3') => {
-                let _ = self.audio_state.pad_tx.send(2);
+            KeyCode::Char('f') => self.toggle_free_tempo(),
+            KeyCode::Char('i') => self.cycle_inputs(),
+            KeyCode::Char('d') => self.toggle_pad_source(),
+            KeyCode::Char('m') => self.toggle_master_source(),
+            KeyCode::Char('1') => self.audio_state.send(EngineCommand::TriggerPad(0)),
+            KeyCode::Char('2') => self.audio_state.send(EngineCommand::TriggerPad(1)),
+            KeyCode::Char('3') => self.audio_state.send(EngineCommand::TriggerPad(2)),
+            KeyCode::Char('4') => self.audio_state.send(EngineCommand::TriggerPad(3)),
+            _ => {}
+        }
+    }
+
+    /// Type the path of the click sample, <Enter> loads it and <Esc> leaves it as it was.
+    fn edit_click_file(&mut self, code: KeyCode) {
+        let Some(input) = self.click_file_input.as_mut() else {
+            return;
+        };
+        match code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
             }
-            KeyCode::Char('4') => {
-                let _ = self.audio_state.pad_tx.send(3);
+            KeyCode::Esc => self.click_file_input = None,
+            KeyCode::Enter => {
+                let path = PathBuf::from(std::mem::take(input));
+                self.click_file_input = None;
+                if path.as_os_str().is_empty() {
+                    return;
+                }
+                match self.audio_state.set_click_file(path) {
+                    Ok(()) => self.metronome.sound = ClickSound::Sample,
+                    Err(error) => {
+                        self.last_error = format!("Can't load the click sample: {}", error);
+                        self.error_count += 1;
+                    }
+                }
             }
             _ => {}
         }
@@ -191,27 +353,89 @@ impl SetUpState {
         if self.loops.len() >= 8 {
             return;
         }
-        let new_loop = LoopState {
-            beat_count: 4,
-            starting: false,
-            layering: false,
-        };
+        let new_loop = LoopState::default();
         self.loops.push(new_loop);
+        if !self.fits_in_memory() {
+            self.loops.pop();
+        }
+    }
+
+    /// How many samples of loop memory the current settings need.
+    fn samples_needed(&self) -> usize {
+        loops::samples_needed(&self.audio_state, &self.loops, self.mbpm, self.free_tempo)
+    }
+
+    /// Whether the loops fit into the memory budget with the current settings.
+    fn fits_in_memory(&self) -> bool {
+        self.samples_needed() <= self.audio_state.buffers.total_samples()
+    }
+
+    fn toggle_free_tempo(&mut self) {
+        self.free_tempo = !self.free_tempo;
+        if !self.fits_in_memory() {
+            self.free_tempo = !self.free_tempo;
+        }
     }
 
     fn decrement(&mut self) {
-        if self.selected == 0 {
-            self.decrement_bpm();
-        } else if let Some(loop_state) = self.loops.get_mut(self.selected - 1) {
-            loop_state.beat_count = 1.max(loop_state.beat_count - 1);
+        match self.selected_row() {
+            Row::Tempo => self.decrement_bpm(),
+            Row::TimeSignature => {
+                let beats_per_bar = &mut self.time_signature.beats_per_bar;
+                *beats_per_bar = 1.max(*beats_per_bar - 1);
+                self.accent_beat = self.accent_beat.min(*beats_per_bar - 1);
+            }
+            Row::CountIn => self.countin.adjust(-1),
+            Row::PreRoll => self.pre_roll = !self.pre_roll,
+            Row::ClickVolume => self
+                .metronome
+                .adjust_volume(self.click_phase, -CLICK_VOLUME_STEP),
+            Row::Accents => self.accent_beat = self.accent_beat.saturating_sub(1),
+            Row::ClickSound | Row::Subdivision | Row::ClickMute => self.cycle_metronome(),
+            Row::ClickFile => {}
+            Row::AutoTune => self
+                .auto_tune
+                .previous_input(self.audio_state.channels.inputs),
+            Row::TuneKey => self.auto_tune.adjust_key(-1),
+            Row::Retune => self.auto_tune.adjust_retune(-RETUNE_STEP),
+            Row::Loop(_) => {
+                if let Some(loop_state) = self.selected_loop() {
+                    loop_state.beat_count = 1.max(loop_state.beat_count - 1);
+                }
+            }
         }
     }
 
     fn increment(&mut self) {
-        if self.selected == 0 {
-            self.increment_bpm();
-        } else if let Some(loop_state) = self.loops.get_mut(self.selected - 1) {
-            loop_state.beat_count = 32.min(loop_state.beat_count + 1);
+        match self.selected_row() {
+            Row::Tempo => self.increment_bpm(),
+            Row::TimeSignature => {
+                let beats_per_bar = &mut self.time_signature.beats_per_bar;
+                *beats_per_bar = TimeSignature::MAX_BEATS_PER_BAR.min(*beats_per_bar + 1);
+            }
+            Row::CountIn => self.countin.adjust(1),
+            Row::PreRoll => self.pre_roll = !self.pre_roll,
+            Row::ClickVolume => self
+                .metronome
+                .adjust_volume(self.click_phase, CLICK_VOLUME_STEP),
+            Row::Accents => {
+                self.accent_beat = (self.accent_beat + 1).min(self.time_signature.beats_per_bar - 1)
+            }
+            Row::ClickSound | Row::Subdivision | Row::ClickMute => self.cycle_metronome(),
+            Row::ClickFile => {}
+            Row::AutoTune => self.auto_tune.next_input(self.audio_state.channels.inputs),
+            Row::TuneKey => self.auto_tune.adjust_key(1),
+            Row::Retune => self.auto_tune.adjust_retune(RETUNE_STEP),
+            Row::Loop(_) => {
+                if let Some(loop_state) = self.selected_loop() {
+                    loop_state.beat_count += 1;
+                }
+                if !self.fits_in_memory()
+                    && let Some(loop_state) = self.selected_loop()
+                {
+                    loop_state.beat_count -= 1;
+                }
+            }
         }
     }
 
@@ -221,8 +445,14 @@ impl SetUpState {
     }
 
     /// Decrease the BPM by the current precision, while keeping the minimum bpm to 30
+    ///
+    /// The BPM is kept if the loops wouldn't fit into memory at the slower tempo.
     fn decrement_bpm(&mut self) {
+        let mbpm = self.mbpm;
         self.mbpm = 30000.max(self.mbpm.saturating_sub(self.precision));
+        if !self.fits_in_memory() {
+            self.mbpm = mbpm;
+        }
     }
 
     fn change_precision(&mut self) {
@@ -237,41 +467,165 @@ impl SetUpState {
         }
     }
 
-    fn select_next(&mut self) {
-        if self.selected == 0 {
-            self.selected = 1;
-        } else if self.selected >= self.loops.len() {
-            self.selected = 0;
-        } else {
-            self.selected += 1;
+    /// Switch the selected metronome setting to its next option.
+    fn cycle_metronome(&mut self) {
+        let row = self.selected_row();
+        let metronome = &mut self.metronome;
+        match row {
+            Row::ClickSound => metronome.sound = metronome.sound.next(),
+            Row::Subdivision => metronome.subdivision = metronome.subdivision.next(),
+            Row::ClickMute => metronome.mute = metronome.mute.next(),
+            _ => {}
         }
     }
 
+    fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % (FIRST_LOOP_ROW + self.loops.len());
+    }
+
     fn select_priv(&mut self) {
         if self.selected == 0 {
-            self.selected = self.loops.len();
+            self.selected = FIRST_LOOP_ROW + self.loops.len() - 1;
         } else {
             self.selected -= 1;
         }
     }
 
     fn toggle_autostart(&mut self) {
-        if self.selected == 0 {
-            return;
-        }
-        if let Some(loop_state) = self.loops.get_mut(self.selected - 1) {
+        if let Some(loop_state) = self.selected_loop() {
             loop_state.starting = !loop_state.starting;
         }
     }
 
     fn toggle_layering(&mut self) {
-        if self.selected == 0 {
-            return;
-        }
-        if let Some(loop_state) = self.loops.get_mut(self.selected - 1) {
+        if let Some(loop_state) = self.selected_loop() {
             loop_state.layering = !loop_state.layering;
         }
     }
+
+    fn cycle_inputs(&mut self) {
+        let channels = self.audio_state.channels;
+        if let Some(loop_state) = self.selected_loop() {
+            loop_state.source.cycle_inputs(channels);
+        }
+    }
+
+    fn toggle_pad_source(&mut self) {
+        if let Some(loop_state) = self.selected_loop() {
+            loop_state.source.pads = !loop_state.source.pads;
+        }
+    }
+
+    fn toggle_master_source(&mut self) {
+        if let Some(loop_state) = self.selected_loop() {
+            loop_state.source.master = !loop_state.source.master;
+        }
+    }
+}
+
+impl SetUpState {
+    /// The marker in front of `row` if it's selected.
+    fn row_marker(&self, row: Row) -> Span<'static> {
+        if self.selected_row() == row {
+            ">> ".green()
+        } else {
+            "".into()
+        }
+    }
+
+    /// The rows of the metronome settings.
+    fn metronome_lines(&self) -> Vec<Line<'static>> {
+        let metronome = &self.metronome;
+        let mut volumes = vec![self.row_marker(Row::ClickVolume), "Click Volume:".into()];
+        for phase in ClickPhase::ALL {
+            let text = format!(" {} {}%", phase.name(), metronome.volume(phase));
+            volumes.push(
+                if self.selected_row() == Row::ClickVolume && phase == self.click_phase {
+                    text.green().bold()
+                } else {
+                    text.yellow()
+                },
+            );
+        }
+
+        let mut accents = vec![self.row_marker(Row::Accents), "Accents: ".into()];
+        let beats_per_bar = self.time_signature.beats_per_bar;
+        for (beat, accent) in metronome
+            .describe_accents(beats_per_bar)
+            .chars()
+            .enumerate()
+        {
+            let text = accent.to_string();
+            accents.push(
+                if self.selected_row() == Row::Accents && beat as u32 == self.accent_beat {
+                    text.green().bold().underlined()
+                } else {
+                    text.yellow()
+                },
+            );
+        }
+
+        vec![
+            Line::from(vec![
+                self.row_marker(Row::ClickSound),
+                "Click: ".into(),
+                metronome.sound.name().yellow(),
+            ]),
+            Line::from(vec![
+                self.row_marker(Row::ClickFile),
+                "Click Sample: ".into(),
+                match (&self.click_file_input, &self.audio_state.click_file) {
+                    (Some(input), _) => format!("{}_", input).green().bold(),
+                    (None, Some(path)) => path.display().to_string().yellow(),
+                    (None, None) => "built-in".yellow(),
+                },
+            ]),
+            Line::from(volumes),
+            Line::from(accents),
+            Line::from(vec![
+                self.row_marker(Row::Subdivision),
+                "Subdivision: ".into(),
+                metronome.subdivision.name().yellow(),
+            ]),
+            Line::from(vec![
+                self.row_marker(Row::ClickMute),
+                "Mute Click: ".into(),
+                metronome.mute.name().yellow(),
+            ]),
+        ]
+    }
+}
+
+impl SetUpState {
+    /// The rows of the pitch correction settings.
+    fn auto_tune_lines(&self) -> Vec<Line<'static>> {
+        let auto_tune = &self.auto_tune;
+        vec![
+            Line::from(vec![
+                self.row_marker(Row::AutoTune),
+                "Auto-tune: ".into(),
+                match auto_tune.input {
+                    Some(input) => format!("input {}", input + 1).green(),
+                    None => "off".red(),
+                },
+                if auto_tune.capture {
+                    " (monitor and loops)".italic()
+                } else {
+                    " (monitor only)".italic()
+                },
+            ]),
+            Line::from(vec![
+                self.row_marker(Row::TuneKey),
+                "Key: ".into(),
+                format!("{} {}", auto_tune.key_name(), auto_tune.scale.name()).yellow(),
+            ]),
+            Line::from(vec![
+                self.row_marker(Row::Retune),
+                "Retune Speed: ".into(),
+                format!("{} ms", auto_tune.retune_ms).yellow(),
+            ]),
+        ]
+    }
 }
 
 impl Widget for &SetUpState {
@@ -284,25 +638,52 @@ impl Widget for &SetUpState {
             "PER ".bold(),
             "(setup) ".italic(),
         ]);
+        let selected_loop = matches!(self.selected_row(), Row::Loop(_));
         let instructions = Line::from(vec![
-            if self.selected == 0 {
-                " Precision ".into()
+            match self.selected_row() {
+                Row::Tempo => " Precision ".into(),
+                Row::TimeSignature => " Beat Unit ".into(),
+                Row::CountIn => " Bars/Beats ".into(),
+                Row::PreRoll => " Toggle ".into(),
+                Row::ClickVolume => " Phase ".into(),
+                Row::Accents => " Accent ".into(),
+                Row::ClickSound | Row::Subdivision | Row::ClickMute => " Next ".into(),
+                Row::ClickFile if self.click_file_input.is_some() => " Load/Cancel ".into(),
+                Row::ClickFile => " Edit ".into(),
+                Row::AutoTune => " Record Tuned ".into(),
+                Row::TuneKey => " Scale ".into(),
+                Row::Retune => " Snap/Glide ".into(),
+                Row::Loop(_) => " Autostart ".into(),
+            },
+            if self.click_file_input.is_some() {
+                "<Enter>/<Esc>".blue().bold()
             } else {
-                " Autostart ".into()
+                "<Tab>".blue().bold()
             },
-            "<Tab>".blue().bold(),
-            if self.selected == 0 {
+            if selected_loop {
+                " Toggle Layering ".into()
+            } else {
                 "".into()
+            },
+            if selected_loop {
+                "<L>".blue().bold()
             } else {
-                " Toggle Layering ".into()
+                "".into()
             },
-            if self.selected == 0 {
+            if selected_loop {
+                " Input/Pads/Master ".into()
+            } else {
                 "".into()
+            },
+            if selected_loop {
+                "<I>/<D>/<M>".blue().bold()
             } else {
-                "<L>".blue().bold()
+                "".into()
             },
             " Add Loop ".into(),
             "<A>".blue().bold(),
+            " Free Tempo ".into(),
+            "<F>".blue().bold(),
             " Finish Setup ".into(),
             "<Space>".blue().bold(),
             " Quit ".into(),
@@ -317,19 +698,52 @@ impl Widget for &SetUpState {
         let bpm = self.mbpm as f64 / 1000.;
         let mut texts = Vec::new();
         let counter_line = Line::from(vec![
-            if self.selected == 0 {
+            if self.selected_row() == Row::Tempo {
                 ">> ".green()
             } else {
                 "".into()
             },
             "BPM: ".into(),
-            bpm.to_string().yellow(),
-            format!(" (+/-{})", self.precision as f32 / 1000.).italic(),
+            if self.free_tempo {
+                "free".yellow()
+            } else {
+                bpm.to_string().yellow()
+            },
+            if self.free_tempo {
+                " (set by recording loop 1)".italic()
+            } else {
+                format!(" (+/-{})", self.precision as f32 / 1000.).italic()
+            },
         ]);
         texts.push(counter_line);
+        texts.push(Line::from(vec![
+            if self.selected_row() == Row::TimeSignature {
+                ">> ".green()
+            } else {
+                "".into()
+            },
+            "Time Signature: ".into(),
+            self.time_signature.to_string().yellow(),
+        ]));
+        texts.push(Line::from(vec![
+            self.row_marker(Row::CountIn),
+            "Count-in: ".into(),
+            self.countin.to_string().yellow(),
+        ]));
+        texts.push(Line::from(vec![
+            self.row_marker(Row::PreRoll),
+            "Record Count-in: ".into(),
+            if self.pre_roll {
+                "last bar".green()
+            } else {
+                "off".red()
+            },
+        ]));
+        texts.extend(self.metronome_lines());
+        texts.extend(self.auto_tune_lines());
         for (i, loop_state) in self.loops.iter().enumerate() {
             let loop_text = Line::from(vec![
-                if self.selected == i + 1 {
+                if self.selected_row() == Row::Loop(i) {
                     ">> ".green()
                 } else {
                     "".into()
@@ -346,9 +760,22 @@ impl Widget for &SetUpState {
                 } else {
                     "overwriting".red()
                 },
+                ", from ".into(),
+                loop_state
+                    .source
+                    .describe(self.audio_state.channels)
+                    .yellow(),
             ]);
             texts.push(loop_text);
         }
+        let buffers = &self.audio_state.buffers;
+        let left = buffers
+            .total_samples()
+            .saturating_sub(self.samples_needed());
+        texts.push(Line::from(vec![
+            "Record time left: ".into(),
+            format!("{:.1}s", buffers.seconds(left)).yellow(),
+        ]));
         texts.push(Line::from(vec![
             format!("Message #{}: ", self.error_count).into(),
             self.last_error.as_str().into(),
@@ -0,0 +1,57 @@
+//! Signals and fixtures shared by the tests.
+
+use crate::audio::{
+    AudioEngine, AudioState, ChannelConfig, EngineCommand, LOOP_COUNT, LoopSource, engine_setup,
+};
+
+/// The sample rate of most tests, low enough to keep them fast.
+pub const SAMPLE_RATE: usize = 4800;
+
+/// A sine at `freq` Hz lasting `seconds` at [`SAMPLE_RATE`], quiet enough to leave the
+/// limiters alone.
+pub fn tone(freq: f32, seconds: f32) -> Vec<f32> {
+    tone_at(SAMPLE_RATE, freq, seconds)
+}
+
+/// Like [`tone`], at `sample_rate`.
+pub fn tone_at(sample_rate: usize, freq: f32, seconds: f32) -> Vec<f32> {
+    (0..(sample_rate as f32 * seconds) as usize)
+        .map(|n| 0.2 * (std::f32::consts::TAU * freq * n as f32 / sample_rate as f32).sin())
+        .collect()
+}
+
+/// How many times `signal` crosses zero.
+pub fn crossings(signal: &[f32]) -> usize {
+    signal
+        .windows(2)
+        .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+        .count()
+}
+
+/// Give every loop ten seconds of memory, for the channels of the default source.
+pub fn provide_buffers(state: &mut AudioState) {
+    let channels = LoopSource::default().channels(state.channels);
+    for index in 0..LOOP_COUNT {
+        state
+            .buffers
+            .provide(index, channels, 10 * SAMPLE_RATE)
+            .unwrap();
+    }
+}
+
+/// An engine at [`SAMPLE_RATE`] and 120 BPM which starts rolling without a count-in with the
+/// next block, recording the first loop for `beats` beats.
+pub fn rolling_engine(channels: ChannelConfig, beats: u32) -> (AudioEngine, AudioState) {
+    let (engine, mut state, _) = engine_setup(SAMPLE_RATE, channels);
+    provide_buffers(&mut state);
+    state.send(EngineCommand::SetTempo(120000));
+    state.send(EngineCommand::Enable);
+    state.send(EngineCommand::ConfigureLoop {
+        index: 0,
+        length: beats,
+        layering: false,
+    });
+    state.send(EngineCommand::ArmLoop(0));
+    state.send(EngineCommand::StartCountIn(0));
+    (engine, state)
+}